@@ -28,9 +28,43 @@ OTHER DEALINGS IN THE SOFTWARE.
 use std::error;
 use std::fmt;
 
-#[derive(Debug)]
+// Exit codes for codo's own failures. Any other exit code comes from the
+// command run in the container, or is 128 + the signal that killed it.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_UNKNOWN: i32 = 70;
+pub const EXIT_ENGINE_NOT_FOUND: i32 = 69;
+pub const EXIT_BUILD_FAILURE: i32 = 73;
+pub const EXIT_INVALID_CONFIG: i32 = 78;
+pub const EXIT_ENGINE_FAILURE: i32 = 125;
+
+pub const EXIT_CODES_HELP: &str = "EXIT STATUS:
+    The exit status of the command run in the container, or 128 + N if it
+    was killed by signal N. codo's own failures exit with:
+     64  invalid command line
+     69  no container engine found
+     70  unexpected error
+     73  image build failed
+     78  invalid configuration
+    125  container engine failed";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ErrorKind {
-    ContainerEngineFailure
+    BuildFailure,
+    ContainerEngineFailure,
+    ContainerEngineNotFound,
+    InvalidConfig,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::BuildFailure => EXIT_BUILD_FAILURE,
+            ErrorKind::ContainerEngineFailure => EXIT_ENGINE_FAILURE,
+            ErrorKind::ContainerEngineNotFound => EXIT_ENGINE_NOT_FOUND,
+            ErrorKind::InvalidConfig => EXIT_INVALID_CONFIG,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -51,8 +85,26 @@ impl error::Error for Error {}
 impl Error {
     pub fn new(kind: ErrorKind, message: &str) -> Error {
         Error {
-            kind: kind,
+            kind,
             message: message.to_string(),
        }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+}
+
+// Get the exit code for any error codo reports
+pub fn exit_code(err: &(dyn error::Error + 'static)) -> i32 {
+    match err.downcast_ref::<Error>() {
+        Some(err) => err.kind().exit_code(),
+        None => EXIT_UNKNOWN,
+    }
+}
+
+// Report a failure to the user and get the exit code for it
+pub fn fail(context: &str, err: Box<dyn error::Error>) -> i32 {
+    eprintln!("{}: {}", context, err);
+    exit_code(err.as_ref())
 }
@@ -26,52 +26,621 @@ OTHER DEALINGS IN THE SOFTWARE.
 */
 
 // Standard
-use std;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
 use std::fs;
 use std::path;
 
 // Crates
-use dirs;
-use log::error;
-use yaml_rust::{Yaml, YamlLoader};
-
-pub const DEFAULT_IMAGE: &str = "default-image";
-
-const DEFAULT_CODO_CONFIG: &str = "
-default-image: fedora
-";
-
-pub fn codo_config() -> Result<Yaml, Box<dyn std::error::Error>> {
-    // Get the default codo config as a fallback
-    let default_codo_config = YamlLoader::load_from_str(DEFAULT_CODO_CONFIG)
-        .expect("Failed to parse default codo config.")[0]
-        .to_owned();
-
-    // Get the codo config file
-    let mut codo_config_file = match codo_config_dir() {
-        Some(dir) => dir,
-        None => return Ok(default_codo_config)
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+// Internal
+use crate::codo_error;
+use crate::engine::{EngineSetting, EscalationSetting};
+use crate::home::PersistentHome;
+use crate::image::PullPolicy;
+use crate::run::{MountDir, MountPoint};
+
+pub const CONFIG_FILE: &str = "codo.yaml";
+const PROJECT_CONFIG_FILE: &str = ".codo.yaml";
+const VCS_DIRS: [&str; 3] = [".git", ".hg", ".svn"];
+
+/// A setting in codo.yaml, so settings are never looked up by a hand-written name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    DefaultImage,
+    ContainerEngine,
+    PrivilegeEscalation,
+    PersistentHome,
+    Mounts,
+    Env,
+    RunArgs,
+    MountPoint,
+    MountDir,
+    RewritePaths,
+    ShimDir,
+    BuildArgs,
+    BuildTarget,
+    BuildSecrets,
+    BuildSsh,
+    Pull,
+    TrustedProjects,
+}
+
+impl Key {
+    pub const ALL: [Key; 17] = [
+        Key::DefaultImage,
+        Key::ContainerEngine,
+        Key::PrivilegeEscalation,
+        Key::PersistentHome,
+        Key::Mounts,
+        Key::Env,
+        Key::RunArgs,
+        Key::MountPoint,
+        Key::MountDir,
+        Key::RewritePaths,
+        Key::ShimDir,
+        Key::BuildArgs,
+        Key::BuildTarget,
+        Key::BuildSecrets,
+        Key::BuildSsh,
+        Key::Pull,
+        Key::TrustedProjects,
+    ];
+
+    // Name of the key in codo.yaml, matching the kebab-case fields of Config
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::DefaultImage => "default-image",
+            Key::ContainerEngine => "container-engine",
+            Key::PrivilegeEscalation => "privilege-escalation",
+            Key::PersistentHome => "persistent-home",
+            Key::Mounts => "mounts",
+            Key::Env => "env",
+            Key::RunArgs => "run-args",
+            Key::MountPoint => "mount-point",
+            Key::MountDir => "mount-dir",
+            Key::RewritePaths => "rewrite-paths",
+            Key::ShimDir => "shim-dir",
+            Key::BuildArgs => "build-args",
+            Key::BuildTarget => "build-target",
+            Key::BuildSecrets => "build-secrets",
+            Key::BuildSsh => "build-ssh",
+            Key::Pull => "pull",
+            Key::TrustedProjects => "trusted-projects",
+        }
+    }
+
+    // Settings that give the container or the build access to the host, which
+    // a cloned repository can only set once the user trusts it
+    pub fn is_host_access(self) -> bool {
+        matches!(self, Key::PrivilegeEscalation | Key::Mounts | Key::Env | Key::RunArgs | Key::BuildArgs
+            | Key::BuildSecrets | Key::BuildSsh | Key::ShimDir | Key::TrustedProjects)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Config {
+    pub default_image: String,
+    pub container_engine: EngineSetting,
+    pub privilege_escalation: EscalationSetting,
+    pub persistent_home: PersistentHome,
+    pub mounts: Vec<Mount>,
+    pub env: Vec<EnvVar>,
+    pub run_args: Vec<String>,
+    pub mount_point: MountPoint,
+    pub mount_dir: MountDir,
+    pub rewrite_paths: bool,
+    pub shim_dir: String,
+    pub build_args: Vec<EnvVar>,
+    pub build_target: Option<String>,
+    pub build_secrets: Vec<BuildSecret>,
+    pub build_ssh: Vec<String>,
+    pub pull: PullPolicy,
+    pub trusted_projects: Vec<String>,
+
+    // Keys that codo doesn't know about
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, Value>,
+
+    // Files the config was read from, lowest precedence first
+    #[serde(skip)]
+    pub layers: Vec<Layer>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            default_image: "fedora".to_string(),
+            container_engine: EngineSetting::Auto,
+            privilege_escalation: EscalationSetting::Auto,
+            persistent_home: PersistentHome::None,
+            mounts: Vec::new(),
+            env: Vec::new(),
+            run_args: Vec::new(),
+            mount_point: MountPoint::Host,
+            mount_dir: MountDir::WorkingDirectory,
+            rewrite_paths: false,
+            shim_dir: "~/.local/bin".to_string(),
+            build_args: Vec::new(),
+            build_target: None,
+            build_secrets: Vec::new(),
+            build_ssh: Vec::new(),
+            pull: PullPolicy::Always,
+            trusted_projects: Vec::new(),
+            unknown: BTreeMap::new(),
+            layers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), String> {
+        if self.default_image.trim().is_empty() {
+            return Err("default-image must not be empty".to_string());
+        }
+        if self.default_image.contains(char::is_whitespace) {
+            return Err(format!("default-image {:?} must not contain whitespace", self.default_image));
+        }
+        if self.shim_dir.trim().is_empty() {
+            return Err("shim-dir must not be empty".to_string());
+        }
+        if let Some(target) = &self.build_target {
+            if target.trim().is_empty() || target.contains(char::is_whitespace) {
+                return Err(format!("build-target {:?} must be a stage name", target));
+            }
+        }
+        if let Some(ssh) = self.build_ssh.iter().find(|ssh| ssh.trim().is_empty() || ssh.contains(char::is_whitespace)) {
+            return Err(format!("build-ssh {:?} must be default or id=socket-or-key", ssh));
+        }
+        Ok(())
+    }
+
+    // Get the directory shims are written to with ~ expanded
+    pub fn shim_dir(&self) -> Option<path::PathBuf> {
+        let host_home = dirs::home_dir()?;
+        Some(path::PathBuf::from(expand_home(&self.shim_dir, &host_home.to_string_lossy())))
+    }
+
+    // Describe which config file set a key
+    pub fn source_of(&self, key: Key) -> String {
+        let key = Value::from(key.name());
+        match self.layers.iter().rev().find(|layer| layer.settings.contains_key(&key)) {
+            Some(layer) => layer.describe(),
+            None => Source::Default.to_string(),
+        }
+    }
+
+    // Describe which config file each item of a list came from
+    pub fn sources_of_items(&self, key: Key) -> Vec<String> {
+        let key = Value::from(key.name());
+        let mut sources: Vec<String> = Vec::new();
+        for layer in self.layers.iter() {
+            if let Some(Value::Sequence(items)) = layer.settings.get(&key) {
+                sources.extend(items.iter().map(|_| layer.describe()));
+            }
+        }
+        sources
+    }
+
+    // Add the config from an image's config directory
+    pub fn with_image(&self, image_name: &str) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut layers: Vec<Layer> = self.layers.iter()
+            .filter(|layer| layer.source != Source::Image)
+            .cloned()
+            .collect();
+        if let Some(mut image_config_file) = image_config_dir(image_name) {
+            image_config_file.push(CONFIG_FILE);
+            if image_config_file.is_file() {
+                // The project config still takes precedence over the image config
+                let image_layer = Layer::read(Source::Image, image_config_file)?;
+                let index = layers.iter().position(|layer| layer.source == Source::Project).unwrap_or(layers.len());
+                layers.insert(index, image_layer);
+            }
+        }
+        merge_layers(layers)
+    }
+}
+
+/// Where a setting came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    CommandLine,
+    User,
+    Image,
+    Project,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self {
+            Source::Default => "default",
+            Source::CommandLine => "command line",
+            Source::User => "user config",
+            Source::Image => "image config",
+            Source::Project => "project config",
+        };
+        write!(f, "{}", source)
+    }
+}
+
+// The settings from a single config file
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub source: Source,
+    pub file: path::PathBuf,
+    settings: Mapping,
+}
+
+impl Layer {
+    fn describe(&self) -> String {
+        format!("{} {}", self.source, self.file.display())
+    }
+
+    fn read(source: Source, file: path::PathBuf) -> Result<Layer, Box<dyn std::error::Error>> {
+        let settings = read_config(&file)?;
+        Ok(Layer { source, file, settings })
+    }
+}
+
+/// A bind mount or volume given as source:target[:options]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    pub options: Vec<String>,
+}
+
+impl TryFrom<String> for Mount {
+    type Error = String;
+
+    fn try_from(mount: String) -> Result<Mount, String> {
+        let mut parts = mount.splitn(3, ':');
+        let source = parts.next().unwrap_or("").to_string();
+        if source.is_empty() {
+            return Err(format!("mount {:?} has no source", mount));
+        }
+
+        // A lone path is mounted at the same path in the container
+        let target = match parts.next() {
+            Some(target) if !target.is_empty() => target.to_string(),
+            Some(_) => return Err(format!("mount {:?} has an empty target", mount)),
+            None => source.to_owned(),
+        };
+
+        let options: Vec<String> = match parts.next() {
+            Some(options) => options.split(',').map(|o| o.to_string()).collect(),
+            None => Vec::new(),
+        };
+        for option in options.iter() {
+            if !["ro", "rw", "z", "Z"].contains(&option.as_str()) {
+                return Err(format!("mount {:?} has unknown option {:?}, expected ro, rw, z or Z", mount, option));
+            }
+        }
+
+        Ok(Mount { source, target, options })
+    }
+}
+
+impl From<Mount> for String {
+    fn from(mount: Mount) -> String {
+        let mut mount_string = format!("{}:{}", mount.source, mount.target);
+        if !mount.options.is_empty() {
+            mount_string.push(':');
+            mount_string.push_str(&mount.options.join(","));
+        }
+        mount_string
+    }
+}
+
+impl Mount {
+    // Get the value of the -v argument with ~ expanded on both sides
+    pub fn volume_arg(&self, container_home: &str) -> String {
+        let host_home = dirs::home_dir().map(|dir| dir.to_string_lossy().into_owned());
+        let source = match host_home {
+            Some(host_home) => expand_home(&self.source, &host_home),
+            None => self.source.to_owned(),
+        };
+        let target = expand_home(&self.target, container_home);
+        String::from(Mount { source, target, options: self.options.clone() })
+    }
+}
+
+fn expand_home(path: &str, home: &str) -> String {
+    if path == "~" {
+        home.to_string()
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        format!("{}/{}", home, rest)
+    } else {
+        path.to_string()
+    }
+}
+
+/// An environment variable given as NAME=value, or NAME to pass through the host's value
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EnvVar {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl TryFrom<String> for EnvVar {
+    type Error = String;
+
+    fn try_from(env_var: String) -> Result<EnvVar, String> {
+        let (name, value) = match env_var.find('=') {
+            Some(i) => (env_var[..i].to_string(), Some(env_var[i + 1..].to_string())),
+            None => (env_var.to_owned(), None),
+        };
+        let valid_name = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(format!("{:?} is not a valid variable name", name));
+        }
+        Ok(EnvVar { name, value })
+    }
+}
+
+impl From<EnvVar> for String {
+    fn from(env_var: EnvVar) -> String {
+        match env_var.value {
+            Some(value) => format!("{}={}", env_var.name, value),
+            None => env_var.name,
+        }
+    }
+}
+
+impl EnvVar {
+    // Get the value of the -e argument, or None if a passed through variable isn't set
+    pub fn env_arg(&self) -> Option<String> {
+        match &self.value {
+            Some(value) => Some(format!("{}={}", self.name, value)),
+            None => env::var(&self.name).ok().map(|value| format!("{}={}", self.name, value)),
+        }
+    }
+}
+
+/// A BuildKit secret given as id=NAME,src=PATH or id=NAME,env=VARIABLE
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BuildSecret {
+    pub id: String,
+    pub src: Option<String>,
+    pub env: Option<String>,
+}
+
+impl TryFrom<String> for BuildSecret {
+    type Error = String;
+
+    fn try_from(secret: String) -> Result<BuildSecret, String> {
+        let mut id: Option<String> = None;
+        let mut src: Option<String> = None;
+        let mut env: Option<String> = None;
+        for part in secret.split(',') {
+            match part.split_once('=') {
+                Some(("id", value)) => id = Some(value.to_string()),
+                Some(("src", value)) | Some(("source", value)) => src = Some(value.to_string()),
+                Some(("env", value)) => env = Some(value.to_string()),
+                _ => return Err(format!("build secret {:?} has unknown part {:?}, expected id, src or env", secret, part)),
+            }
+        }
+
+        let id = match id {
+            Some(id) if !id.is_empty() => id,
+            _ => return Err(format!("build secret {:?} has no id", secret)),
+        };
+        if src.is_some() == env.is_some() {
+            return Err(format!("build secret {:?} needs either a src file or an env variable", secret));
+        }
+        Ok(BuildSecret { id, src, env })
+    }
+}
+
+impl From<BuildSecret> for String {
+    fn from(secret: BuildSecret) -> String {
+        match (secret.src, secret.env) {
+            (Some(src), _) => format!("id={},src={}", secret.id, src),
+            (None, Some(env)) => format!("id={},env={}", secret.id, env),
+            (None, None) => format!("id={}", secret.id),
+        }
+    }
+}
+
+impl BuildSecret {
+    // Name of the file an env secret is written to in the build's temporary directory
+    pub fn file_name(&self) -> String {
+        format!("secret-{}", crate::home::storage_name(&self.id))
+    }
+
+    // Get the value of the --secret argument with ~ expanded in the source file. Env
+    // secrets are passed as a file, since sudo and doas drop the variable before the engine runs.
+    pub fn secret_arg(&self, temp_dir: &path::Path) -> String {
+        let src = match (&self.src, dirs::home_dir()) {
+            (Some(src), Some(host_home)) => expand_home(src, &host_home.to_string_lossy()),
+            (Some(src), None) => src.to_owned(),
+            (None, _) => temp_dir.join(self.file_name()).to_string_lossy().into_owned(),
+        };
+        String::from(BuildSecret { id: self.id.to_owned(), src: Some(src), env: None })
+    }
+}
+
+// The agent socket is given explicitly for the same reason
+pub const SSH_AUTH_SOCK: &str = "SSH_AUTH_SOCK";
+
+// Get the value of a --ssh argument with ~ expanded in the key files
+pub fn ssh_arg(ssh: &str) -> String {
+    let (id, paths) = match ssh.split_once('=') {
+        Some((id, paths)) => (id, paths),
+        None => return match env::var(SSH_AUTH_SOCK) {
+            Ok(socket) => format!("{}={}", ssh, socket),
+            Err(_) => ssh.to_string(),
+        },
+    };
+    match dirs::home_dir() {
+        Some(host_home) => {
+            let host_home = host_home.to_string_lossy();
+            let paths: Vec<String> = paths.split(',').map(|path| expand_home(path, &host_home)).collect();
+            format!("{}={}", id, paths.join(","))
+        },
+        None => ssh.to_string(),
+    }
+}
+
+pub fn codo_config() -> Result<Config, Box<dyn std::error::Error>> {
+    // Get the config files, lowest precedence first
+    let mut layers: Vec<Layer> = Vec::new();
+    if let Some(mut codo_config_file) = codo_config_dir() {
+        codo_config_file.push(CONFIG_FILE);
+        if codo_config_file.is_file() {
+            layers.push(Layer::read(Source::User, codo_config_file)?);
+        }
+    }
+    if let Some(project_config_file) = project_config_file() {
+        debug!("Project config: {:?}", project_config_file);
+        let project_layer = Layer::read(Source::Project, project_config_file)?;
+        check_trust(&project_layer, layers.first())?;
+        layers.push(project_layer);
+    }
+
+    merge_layers(layers)
+}
+
+// Refuse host access settings from a project the user config doesn't trust
+fn check_trust(project_layer: &Layer, user_layer: Option<&Layer>) -> Result<(), Box<dyn std::error::Error>> {
+    let keys: Vec<&str> = Key::ALL.iter()
+        .filter(|key| key.is_host_access() && project_layer.settings.contains_key(&Value::from(key.name())))
+        .map(|key| key.name())
+        .collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let project_dir = match project_layer.file.parent() {
+        Some(dir) => dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()),
+        None => return Ok(()),
+    };
+    let host_home = dirs::home_dir().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
+    let trusted = user_layer
+        .and_then(|layer| layer.settings.get(&Value::from(Key::TrustedProjects.name())))
+        .and_then(|trusted| trusted.as_sequence())
+        .is_some_and(|trusted| trusted.iter()
+            .filter_map(|dir| dir.as_str())
+            .map(|dir| path::PathBuf::from(expand_home(dir, &host_home)))
+            .any(|dir| dir.canonicalize().unwrap_or(dir) == project_dir));
+    if trusted {
+        debug!("Trusted project: {:?}", project_dir);
+        return Ok(());
+    }
+
+    let user_config_file = codo_config_dir().map(|dir| dir.join(CONFIG_FILE)).unwrap_or_else(|| path::PathBuf::from(CONFIG_FILE));
+    let err = format!("{} sets {}, which give the container access to the host. \
+        If you trust this project, add {} to {} in {}.",
+        project_layer.file.display(), keys.join(", "), project_dir.display(), Key::TrustedProjects.name(), user_config_file.display());
+    Err(Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err)))
+}
+
+fn merge_layers(layers: Vec<Layer>) -> Result<Config, Box<dyn std::error::Error>> {
+    // Keys in later files replace keys in earlier files, except lists which are combined
+    let mut merged_config = Mapping::new();
+    for layer in layers.iter() {
+        for (key, value) in layer.settings.iter() {
+            match (merged_config.get_mut(key), value) {
+                (Some(Value::Sequence(merged)), Value::Sequence(items)) => merged.extend(items.iter().cloned()),
+                _ => {
+                    merged_config.insert(key.clone(), value.clone());
+                },
+            }
+        }
+    }
+
+    // Keys missing from every file take their default value
+    let mut codo_config: Config = serde_yaml::from_value(Value::Mapping(merged_config))?;
+    codo_config.layers = layers;
+    debug!("Config: {:?}", codo_config);
+    if let Err(err) = codo_config.validate() {
+        let err = format!("Invalid config: {}", err);
+        let err = codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err);
+        return Err(Box::new(err));
+    }
+
+    Ok(codo_config)
+}
+
+/// Find the directory of the project the working directory is in
+pub fn project_dir() -> Option<path::PathBuf> {
+    let working_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(err) => {
+            error!("Failed to get working directory: {}", err);
+            return None;
+        }
+    };
+
+    // Search up from the working directory, without leaving the repository it's in
+    working_dir.ancestors()
+        .find(|dir| dir.join(PROJECT_CONFIG_FILE).is_file()
+            || VCS_DIRS.iter().any(|vcs_dir| dir.join(vcs_dir).exists()))
+        .map(|dir| dir.to_path_buf())
+}
+
+/// Find the root of the repository the working directory is in
+pub fn repository_dir() -> Option<path::PathBuf> {
+    let working_dir = env::current_dir().ok()?;
+    working_dir.ancestors()
+        .find(|dir| VCS_DIRS.iter().any(|vcs_dir| dir.join(vcs_dir).exists()))
+        .map(|dir| dir.to_path_buf())
+}
+
+pub fn project_config_file() -> Option<path::PathBuf> {
+    let project_config_file = project_dir()?.join(PROJECT_CONFIG_FILE);
+    if project_config_file.is_file() {
+        Some(project_config_file)
+    } else {
+        None
+    }
+}
+
+fn read_config(config_file: &path::Path) -> Result<Mapping, Box<dyn std::error::Error>> {
+    let invalid_config = |err: String| -> Box<dyn std::error::Error> {
+        let err = format!("Failed to parse {:?}: {}", config_file, err);
+        Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err))
+    };
+
+    // An empty file or one with only comments sets nothing
+    let config = fs::read_to_string(config_file)?;
+    let config_value: Value = match serde_yaml::from_str(&config) {
+        Ok(value) => value,
+        Err(_) if config.lines().all(|l| l.trim().is_empty() || l.trim().starts_with('#')) => return Ok(Mapping::new()),
+        Err(err) => return Err(invalid_config(err.to_string())),
+    };
+    let config_mapping = match config_value {
+        Value::Mapping(mapping) => mapping,
+        Value::Null => Mapping::new(),
+        _ => return Err(invalid_config("expected a mapping of settings".to_string())),
     };
-    codo_config_file.push("codo.yaml");
 
-    // Check if the codo config file exists
-    let codo_config_path = path::Path::new(&codo_config_file);
-    if !( codo_config_path.exists() && codo_config_path.is_file() ) {
-        return Ok(default_codo_config);
+    // Check the types here, where errors can point at a line and column
+    let config: Config = match serde_yaml::from_str(&config) {
+        Ok(config) => config,
+        Err(err) => return Err(invalid_config(err.to_string())),
+    };
+    for key in config.unknown.keys() {
+        eprintln!("Warning: unknown key {:?} in {:?}", key, config_file);
     }
 
-    // Parse the config file
-    let codo_config = fs::read_to_string(&codo_config_file)?;
-    let codo_config = YamlLoader::load_from_str(&codo_config)?[0].to_owned();
-    return Ok(codo_config);
+    Ok(config_mapping)
 }
 
 pub fn codo_config_dir() -> Option<path::PathBuf> {
     // Get the codo config dir
-    let mut codo_config_dir = match dirs::home_dir() {
-        Some(dir) => dir,
-        None =>  return None
-    };
+    let mut codo_config_dir = dirs::home_dir()?;
     codo_config_dir.push(".config");
     codo_config_dir.push("codo");
 
@@ -87,18 +656,100 @@ pub fn codo_config_dir() -> Option<path::PathBuf> {
 
 pub fn image_config_dir(image_name: &str) -> Option<path::PathBuf> {
     // Get the image config dir
-    let mut image_config_dir = match codo_config_dir() {
-        Some(dir) => dir,
-        None => return None
-    };
+    let mut image_config_dir = codo_config_dir()?;
     image_config_dir.push("images");
     image_config_dir.push(image_name);
 
     // Check if the directory exists
     let image_config_path = path::Path::new(&image_config_dir);
     if image_config_path.exists() && image_config_path.is_dir() {
-        return Some(image_config_dir);
+        Some(image_config_dir)
     } else {
-        return None;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_config_fields() {
+        let config = match serde_yaml::to_value(Config::default()) {
+            Ok(Value::Mapping(config)) => config,
+            other => panic!("Config didn't serialize to a mapping: {:?}", other),
+        };
+        let mut fields: Vec<&str> = config.iter().filter_map(|(key, _)| key.as_str()).collect();
+        let mut keys: Vec<&str> = Key::ALL.iter().map(|key| key.name()).collect();
+        fields.sort_unstable();
+        keys.sort_unstable();
+        assert_eq!(fields, keys);
+    }
+
+    #[test]
+    fn source_of_key_set_in_layer() {
+        let mut settings = Mapping::new();
+        settings.insert(Value::from("mount-dir"), Value::from("repository"));
+        let layer = Layer { source: Source::Project, file: path::PathBuf::from("/p/.codo.yaml"), settings };
+        let config = Config { layers: vec![layer], ..Config::default() };
+        assert_eq!(config.source_of(Key::MountDir), "project config /p/.codo.yaml");
+        assert_eq!(config.source_of(Key::MountPoint), "default");
+    }
+
+    fn layer(source: Source, file: &str, yaml: &str) -> Layer {
+        let settings = serde_yaml::from_str(yaml).expect("test layer isn't a mapping");
+        Layer { source, file: path::PathBuf::from(file), settings }
+    }
+
+    #[test]
+    fn untrusted_project_cant_set_host_access() {
+        let project = layer(Source::Project, "/nonexistent/p/.codo.yaml", "run-args: [--privileged]\ndefault-image: alpine\n");
+        assert!(check_trust(&project, None).is_err());
+        let other = layer(Source::User, "/u/codo.yaml", "trusted-projects: [/nonexistent/q]\n");
+        assert!(check_trust(&project, Some(&other)).is_err());
+
+        let user = layer(Source::User, "/u/codo.yaml", "trusted-projects: [/nonexistent/p]\n");
+        assert!(check_trust(&project, Some(&user)).is_ok());
+        let harmless = layer(Source::Project, "/nonexistent/p/.codo.yaml", "default-image: alpine\nmount-dir: repository\n");
+        assert!(check_trust(&harmless, None).is_ok());
+    }
+
+    #[test]
+    fn parse_mounts() {
+        let mount = Mount::try_from("~/.ssh:/home/user/.ssh:ro,Z".to_string()).expect("valid mount");
+        assert_eq!((mount.source.as_str(), mount.target.as_str()), ("~/.ssh", "/home/user/.ssh"));
+        assert_eq!(mount.options, vec!["ro", "Z"]);
+        let mount = Mount::try_from("/data".to_string()).expect("valid mount");
+        assert_eq!((mount.target.as_str(), mount.options.len()), ("/data", 0));
+
+        for invalid in [":/data", "", "/data:", "/data:/data:rx", "/data:/data:ro,"].iter() {
+            assert!(Mount::try_from(invalid.to_string()).is_err(), "{:?} parsed", invalid);
+        }
+        assert!(serde_yaml::from_str::<Vec<Mount>>("[/data:/data:rx]").is_err());
+    }
+
+    #[test]
+    fn parse_env_vars() {
+        let env_var = EnvVar::try_from("URL=a=b c".to_string()).expect("valid variable");
+        assert_eq!((env_var.name.as_str(), env_var.value.as_deref()), ("URL", Some("a=b c")));
+        let env_var = EnvVar::try_from("_TERM2".to_string()).expect("valid variable");
+        assert_eq!((env_var.name.as_str(), env_var.value), ("_TERM2", None));
+
+        for invalid in ["", "=value", "2FA=x", "MY-VAR=x", "MY VAR"].iter() {
+            assert!(EnvVar::try_from(invalid.to_string()).is_err(), "{:?} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_build_secrets() {
+        let secret = BuildSecret::try_from("id=npm,src=~/.npmrc".to_string()).expect("valid secret");
+        assert_eq!((secret.id.as_str(), secret.src.as_deref(), secret.env), ("npm", Some("~/.npmrc"), None));
+        let secret = BuildSecret::try_from("env=API_TOKEN,id=token".to_string()).expect("valid secret");
+        assert_eq!((secret.id.as_str(), secret.src, secret.env.as_deref()), ("token", None, Some("API_TOKEN")));
+
+        let invalid = ["id=token", "id=token,src=a,env=B", "src=a", "id=,src=a", "id=token,src=a,mode=0400", "id=token,src"];
+        for invalid in invalid.iter() {
+            assert!(BuildSecret::try_from(invalid.to_string()).is_err(), "{:?} parsed", invalid);
+        }
     }
 }
@@ -0,0 +1,295 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::env;
+use std::fs;
+use std::path;
+
+// Internal
+use crate::codo_error::{self, ErrorKind};
+use crate::config;
+use crate::engine::{self, ContainerEngine, Escalation};
+use crate::image;
+
+const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+const SUBUID_FILE: &str = "/etc/subuid";
+const DOCKER_GROUP: &str = "docker";
+
+/// Result of a single check
+enum Status {
+    Pass,
+    Warn,
+    Fail(ErrorKind),
+}
+
+// Prints each check as it's made and remembers the first failure
+#[derive(Default)]
+struct Report {
+    failure: Option<ErrorKind>,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, status: Status, detail: &str, advice: Option<&str>) {
+        let label = match status {
+            Status::Pass => "ok",
+            Status::Warn => "warn",
+            Status::Fail(kind) => {
+                self.failure.get_or_insert(kind);
+                "FAIL"
+            },
+        };
+        println!("[{:^4}] {}: {}", label, name, detail);
+        if let (Status::Warn, Some(advice)) | (Status::Fail(_), Some(advice)) = (status, advice) {
+            println!("       {}", advice);
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.failure {
+            Some(kind) => kind.exit_code(),
+            None => codo_error::EXIT_SUCCESS,
+        }
+    }
+}
+
+pub fn doctor() -> i32 {
+    let mut report = Report::default();
+
+    // Config files
+    let codo_config = match config::codo_config() {
+        Ok(codo_config) => {
+            let files: Vec<String> = codo_config.layers.iter().map(|layer| layer.file.display().to_string()).collect();
+            let detail = if files.is_empty() { "no config files, using defaults".to_string() } else { files.join(", ") };
+            report.check("config", Status::Pass, &detail, None);
+            codo_config
+        },
+        Err(err) => {
+            report.check("config", Status::Fail(ErrorKind::InvalidConfig), &err.to_string(),
+                Some("Fix the config file named above. The remaining checks use the default settings."));
+            config::Config::default()
+        }
+    };
+    check_image_config(&mut report, &codo_config);
+
+    // Container engines
+    for program in [engine::PODMAN, engine::DOCKER].iter() {
+        check_engine_program(&mut report, program);
+    }
+    match engine::from_config(codo_config.container_engine, codo_config.privilege_escalation) {
+        Ok(engine) => {
+            report.check("selected engine", Status::Pass, engine.name(), None);
+            check_engine_access(&mut report, engine.as_ref());
+        },
+        Err(err) => report.check("selected engine", Status::Fail(ErrorKind::ContainerEngineNotFound), &err.to_string(),
+            Some("Install podman or docker, or set container-engine in codo.yaml.")),
+    };
+
+    check_display(&mut report);
+    check_selinux(&mut report);
+    check_temp_dir(&mut report);
+
+    report.exit_code()
+}
+
+fn check_image_config(report: &mut Report, codo_config: &config::Config) {
+    let images_dir = match config::codo_config_dir() {
+        Some(dir) => dir.join("images"),
+        None => {
+            report.check("image configs", Status::Fail(ErrorKind::InvalidConfig), "failed to get the config directory",
+                Some("Make sure HOME is set and ~/.config/codo can be created."));
+            return;
+        }
+    };
+
+    // Every entry in the images directory should be a directory with a Dockerfile
+    let entries = fs::read_dir(&images_dir).map(|entries| entries.filter_map(|entry| entry.ok()).collect()).unwrap_or_else(|_| Vec::new());
+    if entries.is_empty() {
+        report.check("image configs", Status::Pass, &format!("none in {}, images are used as they are", images_dir.display()), None);
+    }
+    for entry in entries.iter() {
+        let path = entry.path();
+        let name = format!("image config {}", entry.file_name().to_string_lossy());
+        if !path.is_dir() {
+            report.check(&name, Status::Warn, &format!("{} isn't a directory", path.display()),
+                Some("Image configs are directories named after the image. Move or remove this file."));
+        } else if !path.join(image::CODO_DOCKERFILE).is_file() {
+            report.check(&name, Status::Fail(ErrorKind::BuildFailure), &format!("{} has no {}", path.display(), image::CODO_DOCKERFILE),
+                Some(&format!("Add a {} starting with FROM, or remove the directory to use the image as it is.", image::CODO_DOCKERFILE)));
+        } else {
+            let extra_config = if path.join(config::CONFIG_FILE).is_file() { format!(" and {}", config::CONFIG_FILE) } else { String::new() };
+            report.check(&name, Status::Pass, &format!("{}{}", image::CODO_DOCKERFILE, extra_config), None);
+        }
+    }
+
+    // The default image doesn't need a config directory
+    let detail = match config::image_config_dir(&codo_config.default_image) {
+        Some(dir) => format!("{} from {}", codo_config.default_image, dir.join(image::CODO_DOCKERFILE).display()),
+        None => format!("{} used as it is, no {} in {}", codo_config.default_image, image::CODO_DOCKERFILE, images_dir.join(&codo_config.default_image).display()),
+    };
+    report.check("default image", Status::Pass, &detail, None);
+}
+
+fn check_engine_program(report: &mut Report, program: &str) {
+    let path = match engine::find_program(program) {
+        Some(path) => path,
+        None => {
+            report.check(program, Status::Warn, "not installed", None);
+            return;
+        }
+    };
+
+    let version_command = vec![path.to_string_lossy().into_owned(), "--version".to_string()];
+    let inherit_io = false;
+    match image::run_command(&version_command, inherit_io) {
+        Ok(output) => {
+            let version = String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or("").trim().to_string();
+            report.check(program, Status::Pass, &format!("{} at {}", version, path.display()), None);
+        },
+        Err(err) => report.check(program, Status::Fail(ErrorKind::ContainerEngineFailure), &err.to_string(),
+            Some(&format!("Reinstall {} or remove {} from the PATH.", program, path.display()))),
+    };
+}
+
+fn check_engine_access(report: &mut Report, engine: &dyn ContainerEngine) {
+    let escalation = match engine.escalation().program() {
+        Some(program) => format!("through {}", program),
+        None => "without privilege escalation".to_string(),
+    };
+
+    if engine.name() == engine::PODMAN {
+        // Rootless podman needs subordinate ids for the user
+        let username = users::get_current_username().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let has_subuid = fs::read_to_string(SUBUID_FILE)
+            .map(|subuid| subuid.lines().any(|line| line.split(':').next() == Some(username.as_str())))
+            .unwrap_or(false);
+        if engine.escalation() == Escalation::None && users::get_current_uid() != 0 && !has_subuid {
+            report.check("rootless podman", Status::Warn, &format!("{} has no entry in {}", username, SUBUID_FILE),
+                Some(&format!("Add one with: sudo usermod --add-subuids 100000-165535 --add-subgids 100000-165535 {}", username)));
+        }
+
+        // Podman has no daemon, so asking for its info is enough
+        let info_command = engine.command(&["info"]);
+        let inherit_io = false;
+        match image::run_command(&info_command, inherit_io) {
+            Ok(_) => report.check("engine access", Status::Pass, &format!("podman runs {}", escalation), None),
+            Err(err) => report.check("engine access", Status::Fail(ErrorKind::ContainerEngineFailure), &err.to_string(),
+                Some("Run `podman info` to see what's wrong with the podman setup.")),
+        };
+        return;
+    }
+
+    // Docker needs its daemon and either the docker group or escalation
+    let in_docker_group = users::get_group_by_name(DOCKER_GROUP)
+        .map(|group| users::group_access_list().map(|groups| groups.iter().any(|g| g.gid() == group.gid())).unwrap_or(false))
+        .unwrap_or(false);
+    let socket_reachable = engine::docker_socket_reachable();
+    if users::get_current_uid() == 0 {
+        report.check("docker permissions", Status::Pass, "running as root", None);
+    } else if socket_reachable && engine.escalation() == Escalation::None {
+        // Rootless docker or a socket the user owns needs neither the group nor escalation
+        report.check("docker permissions", Status::Pass, "socket reachable without escalation", None);
+    } else if in_docker_group {
+        report.check("docker permissions", Status::Pass, &format!("member of the {} group", DOCKER_GROUP), None);
+    } else if engine.escalation() != Escalation::None {
+        report.check("docker permissions", Status::Pass, &format!("docker runs {}", escalation), None);
+    } else {
+        report.check("docker permissions", Status::Fail(ErrorKind::ContainerEngineFailure),
+            &format!("not in the {} group and privilege-escalation is none", DOCKER_GROUP),
+            Some(&format!("Add yourself to the group with `sudo usermod -aG {} $USER`, or set privilege-escalation to sudo or doas.", DOCKER_GROUP)));
+    }
+
+    if socket_reachable {
+        report.check("docker daemon", Status::Pass, "socket reachable", None);
+    } else if engine.escalation() != Escalation::None {
+        report.check("docker daemon", Status::Warn, &format!("socket not reachable without escalation, docker runs {}", escalation),
+            Some("If docker still fails, start the daemon with `sudo systemctl start docker`."));
+    } else {
+        report.check("docker daemon", Status::Fail(ErrorKind::ContainerEngineFailure), "socket not reachable",
+            Some("Start the daemon with `sudo systemctl start docker`, or check DOCKER_HOST and `docker context ls`."));
+    }
+}
+
+fn check_display(report: &mut Report) {
+    let display = match env::var("DISPLAY") {
+        Ok(display) => display,
+        Err(_) => {
+            report.check("X11", Status::Warn, "DISPLAY isn't set", Some("Graphical programs won't be able to open windows."));
+            return;
+        }
+    };
+
+    // A local display :N has a socket named XN
+    let display_number = display.strip_prefix(':').and_then(|rest| rest.split('.').next());
+    let socket = display_number.map(|number| path::Path::new(X11_SOCKET_DIR).join(format!("X{}", number)));
+    match socket {
+        Some(socket) if !socket.exists() => report.check("X11", Status::Warn, &format!("DISPLAY={} but {} doesn't exist", display, socket.display()),
+            Some("Graphical programs in the container won't reach the X server.")),
+        _ if !path::Path::new(X11_SOCKET_DIR).is_dir() => report.check("X11", Status::Warn, &format!("{} doesn't exist", X11_SOCKET_DIR),
+            Some("Remote displays aren't reachable from the container.")),
+        _ => report.check("X11", Status::Pass, &format!("DISPLAY={}", display), None),
+    };
+}
+
+fn check_selinux(report: &mut Report) {
+    match fs::read_to_string(SELINUX_ENFORCE).map(|mode| mode.trim().to_string()) {
+        Ok(mode) if mode == "1" => report.check("SELinux", Status::Warn, "enforcing",
+            Some("Add the z or Z option to mounts in codo.yaml if the container can't read them, for example ~/.ssh:~/.ssh:ro,Z.")),
+        Ok(_) => report.check("SELinux", Status::Pass, "permissive", None),
+        Err(_) => report.check("SELinux", Status::Pass, "disabled", None),
+    };
+}
+
+fn check_temp_dir(report: &mut Report) {
+    // Builds write their Dockerfile to a private directory in the temp dir
+    let temp_dir = env::temp_dir();
+    match image::TempDir::new() {
+        Ok(_) => report.check("temp dir", Status::Pass, &format!("{} is writable", temp_dir.display()), None),
+        Err(err) => report.check("temp dir", Status::Fail(ErrorKind::BuildFailure), &format!("{}: {}", temp_dir.display(), err),
+            Some("Set TMPDIR to a writable directory.")),
+    };
+
+    // Concurrent builds of an image wait on a lock file in the cache dir
+    let lock_dir = match image::build_lock_dir() {
+        Some(dir) => dir,
+        None => {
+            report.check("build locks", Status::Fail(ErrorKind::BuildFailure), "failed to get the cache directory",
+                Some("Set HOME or XDG_CACHE_HOME."));
+            return;
+        }
+    };
+    let probe = lock_dir.join(".codo-doctor");
+    let writable = fs::create_dir_all(&lock_dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+    match writable {
+        Ok(_) => report.check("build locks", Status::Pass, &format!("{} is writable", lock_dir.display()), None),
+        Err(err) => report.check("build locks", Status::Fail(ErrorKind::BuildFailure), &format!("{}: {}", lock_dir.display(), err),
+            Some("Set XDG_CACHE_HOME to a writable directory.")),
+    };
+}
@@ -0,0 +1,316 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::env;
+use std::error;
+use std::fs;
+use std::os::unix::net::UnixStream;
+use std::path;
+use std::process;
+
+// Crate
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+// Internal
+use crate::codo_error;
+
+pub const DOCKER: &str = "docker";
+pub const PODMAN: &str = "podman";
+pub const SUDO: &str = "sudo";
+pub const DOAS: &str = "doas";
+pub const ENV: &str = "env";
+
+const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const DOCKER_SOCKET_NAME: &str = "docker.sock";
+
+/// Container engine chosen in codo.yaml
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineSetting {
+    Auto,
+    Docker,
+    Podman,
+}
+
+/// Privilege escalation chosen in codo.yaml
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EscalationSetting {
+    Auto,
+    Sudo,
+    Doas,
+    None,
+}
+
+/// How the engine is given root privileges
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Escalation {
+    Sudo,
+    Doas,
+    None,
+}
+
+impl Escalation {
+    /// Get the escalation chosen in the config, with auto being None
+    fn from_setting(setting: EscalationSetting) -> Option<Escalation> {
+        match setting {
+            EscalationSetting::Auto => None,
+            EscalationSetting::Sudo => Some(Escalation::Sudo),
+            EscalationSetting::Doas => Some(Escalation::Doas),
+            EscalationSetting::None => Some(Escalation::None),
+        }
+    }
+
+    /// Pick whichever escalation program is installed
+    fn detect() -> Escalation {
+        if users::get_current_uid() == 0 {
+            Escalation::None
+        } else if find_program(SUDO).is_some() {
+            Escalation::Sudo
+        } else if find_program(DOAS).is_some() {
+            Escalation::Doas
+        } else {
+            Escalation::None
+        }
+    }
+
+    pub fn program(&self) -> Option<&str> {
+        match self {
+            Escalation::Sudo => Some(SUDO),
+            Escalation::Doas => Some(DOAS),
+            Escalation::None => None,
+        }
+    }
+}
+
+/// A container engine that codo can drive from the command line
+pub trait ContainerEngine {
+    /// Name of the engine executable, as used in codo.yaml
+    fn name(&self) -> &str;
+
+    /// Privilege escalation used to invoke the engine
+    fn escalation(&self) -> Escalation;
+
+    /// Extra arguments needed by `run` for this engine
+    fn run_args(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Command used to invoke the engine, before any subcommand
+    fn base_command(&self) -> Vec<String> {
+        let mut command: Vec<String> = Vec::new();
+        if let Some(program) = self.escalation().program() {
+            command.push(program.to_string());
+        }
+        command.push(self.name().to_string());
+        command
+    }
+
+    /// Create an engine command from a subcommand and its arguments
+    fn command(&self, args: &[&str]) -> Vec<String> {
+        let mut command = self.base_command();
+        command.extend(args.iter().map(|s| s.to_string()));
+        command
+    }
+
+    /// Create a build command that can use BuildKit secret and SSH mounts
+    fn buildkit_command(&self, args: &[&str]) -> Vec<String> {
+        self.command(args)
+    }
+}
+
+pub struct Docker {
+    escalation: Escalation,
+}
+
+impl ContainerEngine for Docker {
+    fn name(&self) -> &str {
+        DOCKER
+    }
+
+    fn escalation(&self) -> Escalation {
+        self.escalation
+    }
+
+    fn buildkit_command(&self, args: &[&str]) -> Vec<String> {
+        // Older versions only use BuildKit when asked, and sudo drops the variable from codo's environment
+        let mut command: Vec<String> = Vec::new();
+        if let Some(program) = self.escalation.program() {
+            command.push(program.to_string());
+        }
+        command.extend([ENV, "DOCKER_BUILDKIT=1", DOCKER].iter().map(|s| s.to_string()));
+        command.extend(args.iter().map(|s| s.to_string()));
+        command
+    }
+}
+
+pub struct Podman {
+    escalation: Escalation,
+}
+
+impl ContainerEngine for Podman {
+    fn name(&self) -> &str {
+        PODMAN
+    }
+
+    fn escalation(&self) -> Escalation {
+        self.escalation
+    }
+
+    fn run_args(&self) -> Vec<String> {
+        // Map the user to the same uid inside a rootless container
+        if self.escalation != Escalation::None || users::get_current_uid() == 0 {
+            return Vec::new();
+        }
+        vec!["--userns=keep-id".to_string()]
+    }
+}
+
+/// Select the container engine and privilege escalation named in the config
+pub fn from_config(engine_setting: EngineSetting, escalation_setting: EscalationSetting) -> Result<Box<dyn ContainerEngine>, Box<dyn error::Error>> {
+    let engine_setting = match engine_setting {
+        EngineSetting::Auto => match detect() {
+            Some(setting) => setting,
+            None => {
+                let err = format!("No container engine found. Install {} or {}.", PODMAN, DOCKER);
+                let err = codo_error::Error::new(codo_error::ErrorKind::ContainerEngineNotFound, &err);
+                return Err(Box::new(err));
+            }
+        },
+        setting => setting,
+    };
+    let escalation = Escalation::from_setting(escalation_setting);
+
+    match engine_setting {
+        EngineSetting::Podman => {
+            // Podman is daemonless and runs rootless as the current user
+            let escalation = escalation.unwrap_or(Escalation::None);
+            debug!("Podman privilege escalation: {:?}", escalation);
+            Ok(Box::new(Podman { escalation }))
+        },
+        _ => {
+            // Only escalate if the daemon can't be reached directly
+            let escalation = escalation.unwrap_or_else(|| {
+                if docker_socket_reachable() {
+                    Escalation::None
+                } else {
+                    Escalation::detect()
+                }
+            });
+            debug!("Docker privilege escalation: {:?}", escalation);
+            Ok(Box::new(Docker { escalation }))
+        },
+    }
+}
+
+/// Find the engine to use: a running Docker daemon, then podman, then an installed docker
+fn detect() -> Option<EngineSetting> {
+    // Switching engines would lose the images and home volumes of an existing Docker setup
+    let docker = find_program(DOCKER).filter(|path| !is_podman_wrapper(path));
+    let setting = if docker.is_some() && docker_socket_reachable() {
+        info!("Using {} because its daemon is reachable", DOCKER);
+        EngineSetting::Docker
+    } else if find_program(PODMAN).is_some() {
+        info!("Using {} because it is installed", PODMAN);
+        EngineSetting::Podman
+    } else if docker.is_some() {
+        info!("Using {} because it is installed", DOCKER);
+        EngineSetting::Docker
+    } else {
+        return None;
+    };
+    Some(setting)
+}
+
+// Check if a docker executable is the script podman-docker installs to run podman
+fn is_podman_wrapper(program: &path::Path) -> bool {
+    const MAX_SCRIPT_SIZE: u64 = 64 * 1024;
+    match fs::metadata(program) {
+        Ok(metadata) if metadata.len() <= MAX_SCRIPT_SIZE => fs::read(program)
+            .map(|contents| contents.windows(PODMAN.len()).any(|window| window == PODMAN.as_bytes()))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Check if the current user can talk to the Docker daemon without escalation
+pub fn docker_socket_reachable() -> bool {
+    if let Ok(host) = env::var("DOCKER_HOST") {
+        return match host.strip_prefix("unix://") {
+            Some(socket) => socket_connects(socket),
+            // Remote daemons don't go through a local socket
+            None => true,
+        };
+    }
+
+    // Rootful docker and rootless docker have well known sockets
+    let mut sockets = vec![path::PathBuf::from(DEFAULT_DOCKER_SOCKET)];
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        sockets.push(path::Path::new(&runtime_dir).join(DOCKER_SOCKET_NAME));
+    }
+    if sockets.iter().any(socket_connects) {
+        return true;
+    }
+
+    // Let docker itself resolve the active context
+    if find_program(DOCKER).is_none() {
+        return false;
+    }
+    let status = process::Command::new(DOCKER)
+        .args(["version", "--format", "{{.Server.Version}}"])
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+    match status {
+        Ok(status) => status.success(),
+        Err(err) => {
+            debug!("Failed to run docker version: {}", err);
+            false
+        }
+    }
+}
+
+fn socket_connects<P: AsRef<path::Path>>(socket: P) -> bool {
+    match UnixStream::connect(&socket) {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("Failed to connect to {}: {}", socket.as_ref().display(), err);
+            false
+        }
+    }
+}
+
+/// Search the PATH for an executable
+pub fn find_program(program: &str) -> Option<path::PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
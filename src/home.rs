@@ -0,0 +1,134 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::error;
+use std::fs;
+use std::path;
+
+// Crate
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+// Internal
+use crate::codo_error;
+use crate::engine::ContainerEngine;
+use crate::image;
+
+/// Where the home directory of a container is kept between runs
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistentHome {
+    None,
+    Volume,
+    Directory,
+}
+
+// Volume and directory names can't contain the slashes and colons of an image name
+pub fn storage_name(image_name: &str) -> String {
+    image_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '-' })
+        .collect()
+}
+
+// Volumes are shared by everyone using the daemon, so each user gets their own
+pub fn volume_name(image_name: &str) -> String {
+    format!("{}-home-{}", storage_name(&image::codo_tag_suffix()), storage_name(image_name))
+}
+
+pub fn host_dir(image_name: &str) -> Option<path::PathBuf> {
+    let mut host_dir = dirs::data_dir()?;
+    host_dir.push("codo");
+    host_dir.push("homes");
+    host_dir.push(storage_name(image_name));
+    Some(host_dir)
+}
+
+// Get where the home of an image is stored on the host
+pub fn location(persistent_home: PersistentHome, image_name: &str) -> Option<String> {
+    match persistent_home {
+        PersistentHome::None => None,
+        PersistentHome::Volume => Some(volume_name(image_name)),
+        PersistentHome::Directory => host_dir(image_name)
+            .map(|dir| dir.to_string_lossy().into_owned()),
+    }
+}
+
+pub fn mount_args(persistent_home: PersistentHome, image_name: &str) -> Vec<String> {
+    let user = match image::current_user() {
+        Some(user) => user,
+        None => return Vec::new()
+    };
+
+    match location(persistent_home, image_name) {
+        Some(location) => {
+            debug!("Persistent home: {}", location);
+            vec!["-v".to_string(), format!("{}:{}", location, user.home())]
+        },
+        None => Vec::new()
+    }
+}
+
+pub fn prepare(persistent_home: PersistentHome, image_name: &str) -> Result<(), Box<dyn error::Error>> {
+    // Create the host directory so it's owned by the user rather than the engine
+    if persistent_home == PersistentHome::Directory {
+        match host_dir(image_name) {
+            Some(dir) => fs::create_dir_all(&dir)?,
+            None => {
+                let err = "Failed to get the data directory for the persistent home";
+                let err = codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, err);
+                return Err(Box::new(err));
+            }
+        };
+    }
+    Ok(())
+}
+
+pub fn remove(engine: &dyn ContainerEngine, persistent_home: PersistentHome, image_name: &str) -> Result<(), Box<dyn error::Error>> {
+    match persistent_home {
+        PersistentHome::None => (),
+        PersistentHome::Volume => {
+            // Only remove the volume if it exists
+            let volume_name = volume_name(image_name);
+            let list_command = engine.command(&["volume", "ls", "--quiet"]);
+            let inherit_io = false;
+            let volumes = image::run_command(&list_command, inherit_io)?;
+            if String::from_utf8(volumes.stdout)?.lines().any(|v| v.trim() == volume_name) {
+                let remove_command = engine.command(&["volume", "rm", &volume_name]);
+                image::run_command(&remove_command, inherit_io)?;
+            }
+        },
+        PersistentHome::Directory => {
+            if let Some(dir) = host_dir(image_name) {
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                }
+            }
+        },
+    };
+    Ok(())
+}
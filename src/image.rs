@@ -26,26 +26,89 @@ OTHER DEALINGS IN THE SOFTWARE.
 */
 
 // Standard
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::error;
+use std::ffi;
+use std::fmt;
 use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
 use std::path;
 use std::process::{self, Command, Stdio};
+use std::thread;
 
 // Crate
 use log::error;
 use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGWINCH};
+use signal_hook::iterator::SignalsInfo;
+use signal_hook::iterator::exfiltrator::WithOrigin;
 
 // Internal
-use crate::config;
 use crate::codo_error;
+use crate::config;
+use crate::engine::ContainerEngine;
+use crate::home;
+use crate::lock;
+use crate::lsp;
+use crate::paths;
+use crate::run::MANAGED_LABEL;
 
+// Dockerfile in an image config directory
+pub const CODO_DOCKERFILE: &str = "CodoDockerfile";
 
-pub fn add_codo_tag(image_name: &str) -> String {
+pub const DOCKERFILE_HASH_LABEL: &str = "codo.dockerfile-hash";
+pub const CONTEXT_HASH_LABEL: &str = "codo.context-hash";
+
+// Labels that keep codo builds recognisable after a newer build takes their tag
+pub const IMAGE_LABEL: &str = "codo.image";
+pub const USER_LABEL: &str = "codo.user";
+
+const DOCKER_HUB: &str = "docker.io";
+
+/// When the base images of a build are pulled from their registry
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PullPolicy {
+    Always,
+    Missing,
+    Never,
+}
+
+pub const PULL_POLICIES: [&str; 3] = ["always", "missing", "never"];
+
+impl PullPolicy {
+    pub fn from_name(name: &str) -> Option<PullPolicy> {
+        match name {
+            "always" => Some(PullPolicy::Always),
+            "missing" => Some(PullPolicy::Missing),
+            "never" => Some(PullPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PullPolicy::Always => "always",
+            PullPolicy::Missing => "missing",
+            PullPolicy::Never => "never",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn codo_tag_suffix() -> String {
     // Get the codo suffix
     let default_tag = "codo".to_string();
-    let tag = match users::get_current_username() {
+    match users::get_current_username() {
         Some(user) => {
             match user.into_string() {
                 Ok(user) => format!("{}-{}", default_tag, user),
@@ -56,194 +119,751 @@ pub fn add_codo_tag(image_name: &str) -> String {
             }
         },
         None => default_tag
-    };
+    }
+}
+
+pub fn add_codo_tag(image_name: &str) -> String {
+    let tag = codo_tag_suffix();
 
     // Check if a tag was passed
-    if image_name.to_string().contains(":") {
+    if image_name.contains(':') {
         format!("{}-{}", image_name, tag)
     } else {
         format!("{}:latest-{}", image_name, tag)
     }
 }
 
-pub fn build(image_name: &str) -> Result<(), Box<dyn error::Error>> {
-    // Create the directory for the temporary dockerfile
-    let mut temp_dockerfile_path = env::temp_dir();
-    temp_dockerfile_path.push("codo");
-    fs::create_dir_all(&temp_dockerfile_path)?;
-
-    // Get the Dockerfile
-    let dockerfile: String;
-    let build_dir: path::PathBuf;
+fn is_codo_tag(tag: &str) -> bool {
+    tag.ends_with(&format!("-{}", codo_tag_suffix()))
+}
 
-    // Get the image config directory
-    match config::image_config_dir(image_name) {
-        Some(mut image_config_dir) => {
-            // Record the build directory
-            build_dir = image_config_dir.clone();
+pub fn human_size(size: u64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
 
-            // Read the Dockerfile
-            image_config_dir.push("CodoDockerfile");
-            dockerfile = fs::read_to_string(&image_config_dir)?;
-        },
-        None => {
-            // Set the build directory to the temporary dockerfile path
-            build_dir = temp_dockerfile_path.clone();
+pub struct User {
+    pub name: String,
+    pub uid: users::uid_t,
+    pub gid: users::gid_t,
+}
 
-            // Create a default dockerfile
-            dockerfile = format!("FROM {}\n", image_name);
-        }
-    };
+impl User {
+    // Home directory of the user inside codo images
+    pub fn home(&self) -> String {
+        format!("/home/{}", self.name)
+    }
+}
 
-    // Get the username
-    let mut user_found = false;
-    let username: String;
+pub fn current_user() -> Option<User> {
     let uid: users::uid_t = users::get_current_uid();
-    let gid: users::uid_t;
     match users::get_user_by_uid(uid) {
         Some(user) => {
-            username = match user.name().to_owned().into_string() {
+            let name = match user.name().to_owned().into_string() {
                 Ok(name) => name,
                 Err(_) => {
                     error!("Failed to get username as string");
                     "user".to_string()
                 }
             };
-            gid = user.primary_group_id();
-            user_found = true;
+            Some(User { name, uid, gid: user.primary_group_id() })
         }
         None => {
             error!("Failed to get user information");
-            username = "".to_string();
-            gid = 0;
+            None
         }
-    };
+    }
+}
+
+// Everything that goes into building a codo image
+pub struct BuildPlan {
+    pub image_name: String,
+    pub image_with_tag: String,
+    pub dockerfile: String,
+    pub build_dir: Option<path::PathBuf>,
+    pub labels: BTreeMap<String, String>,
+    pub build_args: Vec<config::EnvVar>,
+    pub target: Option<String>,
+    pub secrets: Vec<config::BuildSecret>,
+    pub ssh: Vec<String>,
+    pub pull: PullPolicy,
+    pub base_images: Vec<String>,
+}
+
+impl BuildPlan {
+    // Check if an image was built from this plan
+    pub fn is_current(&self, image_info: &ImageInfo) -> bool {
+        self.labels.iter().all(|(key, value)| image_info.labels.get(key) == Some(value))
+    }
+
+    // Secret and SSH mounts need BuildKit
+    fn needs_buildkit(&self) -> bool {
+        !self.secrets.is_empty() || !self.ssh.is_empty()
+    }
+}
+
+// Shell script that creates the user with the tools of the base image's distro.
+// Each line is joined into a single RUN instruction, so every line has to end
+// where a shell statement can.
+const PROVISION_USER: &str = r#"set -e;
+find_name() { while IFS=: read -r entry _ id _; do if [ "$id" = "$2" ]; then echo "$entry"; return; fi; done < "$1"; };
+alpine=$( if [ -r /etc/os-release ]; then . /etc/os-release; fi; for id in ${ID:-} ${ID_LIKE:-}; do if [ "$id" = alpine ]; then echo yes; exit; fi; done );
+shell=/bin/sh;
+for candidate in /bin/bash /usr/bin/bash /bin/zsh /usr/bin/zsh /bin/ash /bin/sh; do if [ -x "$candidate" ]; then shell=$candidate; break; fi; done;
+remove_user() { if [ -n "$alpine" ]; then deluser "$1"; elif command -v userdel >/dev/null 2>&1; then userdel "$1"; else sed -i "/^$1:/d" /etc/passwd; sed -i "/^$1:/d" /etc/shadow 2>/dev/null || true; fi; };
+existing_user=$(find_name /etc/passwd "$uid");
+if [ -n "$existing_user" ] && [ "$existing_user" != "$name" ]; then remove_user "$existing_user"; existing_user=; fi;
+if [ -z "$existing_user" ] && grep -q "^$name:" /etc/passwd; then remove_user "$name"; fi;
+group=$(find_name /etc/group "$gid");
+if [ -z "$group" ]; then group=$name; if grep -q "^$group:" /etc/group; then group=codo-$name; fi; if [ -n "$alpine" ]; then addgroup -g "$gid" "$group"; elif command -v groupadd >/dev/null 2>&1; then groupadd -g "$gid" "$group"; else echo "$group:x:$gid:" >> /etc/group; fi; fi;
+if [ -n "$existing_user" ]; then :; elif [ -n "$alpine" ]; then adduser -D -H -u "$uid" -G "$group" -h "$home" -s "$shell" "$name"; elif command -v useradd >/dev/null 2>&1; then useradd -M -u "$uid" -g "$gid" -d "$home" -s "$shell" "$name"; else echo "$name:x:$uid:$gid:$name:$home:$shell" >> /etc/passwd; fi;
+mkdir -p "$home";
+chown -R "$uid:$gid" "$home";
+if [ -d /etc/sudoers.d ]; then echo "$name ALL=(ALL) NOPASSWD: ALL" > /etc/sudoers.d/codo; chmod 0440 /etc/sudoers.d/codo; fi"#;
+
+fn user_layer(user: &User) -> String {
+    let mut user_layer = format!("
+USER root
+RUN name='{name}'; uid={uid}; gid={gid}; home='{home}'; \\
+",
+        name = user.name,
+        uid = user.uid,
+        gid = user.gid,
+        home = user.home());
+    user_layer.push_str(&PROVISION_USER.lines().collect::<Vec<&str>>().join(" \\\n"));
+    user_layer.push_str(&format!("
+USER {name}
+ENV HOME {home}
+",
+        name = user.name,
+        home = user.home()));
+    user_layer
+}
+
+// Get the Dockerfile of an image before codo adds to it, and the directory it's built in
+pub fn read_dockerfile(image_name: &str) -> Result<(String, Option<path::PathBuf>), Box<dyn error::Error>> {
+    // Get the image config directory
+    match config::image_config_dir(image_name) {
+        Some(image_config_dir) => {
+            // Read the Dockerfile
+            let dockerfile = fs::read_to_string(image_config_dir.join(CODO_DOCKERFILE))?;
+            Ok((dockerfile, Some(image_config_dir)))
+        },
+        // Create a default dockerfile, built from the temporary dockerfile path
+        None => Ok((format!("FROM {}\n", image_name), None))
+    }
+}
+
+pub fn plan_build(image_name: &str, image_config: &config::Config) -> Result<BuildPlan, Box<dyn error::Error>> {
+    // Get the Dockerfile with its base images pinned by the project's lock file
+    let (dockerfile, build_dir) = read_dockerfile(image_name)?;
+    let dockerfile = lock::pin_dockerfile(image_name, &dockerfile)?;
 
     // Create the extended dockerfile
     let mut extended_dockerfile: String = dockerfile.to_owned();
-    if user_found {
-        extended_dockerfile.push_str(format!("
-            RUN export uid={uid} gid={gid}
-            RUN mkdir -p /home/{username}
-            RUN echo \"{username}:x:${{uid}}:${{gid}}:{username},,,:/home/{username}:/bin/bash\" >> /etc/passwd
-            RUN echo \"{username}:x:${{uid}}:\" >> /etc/group
-            RUN echo \"{username} ALL=(ALL) NOPASSWD: ALL\" >> /etc/sudoers
-            RUN chmod 0440 /etc/sudoers
-            RUN chown ${{uid}}:${{gid}} -R /home/{username}
-            USER {username}
-            ENV HOME /home/{username}
-            ", 
-            username = username,
-            uid = uid,
-            gid = gid).as_str());
-    }
-    debug!("Building Dockerfile: \n {}", extended_dockerfile);
-
-    // Write the final dockerfile
-    temp_dockerfile_path.push("Dockerfile");
-    fs::write(&temp_dockerfile_path, extended_dockerfile)?;
-    
-    // Get the image tag
-    let image_with_tag = add_codo_tag(image_name);
+    if let Some(user) = current_user() {
+        extended_dockerfile.push_str(&user_layer(&user));
+    }
+
+    // Stamp the image with what it was built from
+    let mut labels: BTreeMap<String, String> = BTreeMap::new();
+    let mut dockerfile_hash = Sha256::new();
+    dockerfile_hash.update(extended_dockerfile.as_bytes());
+    labels.insert(DOCKERFILE_HASH_LABEL.to_string(), format!("{:x}", dockerfile_hash.finalize()));
+    let mut context_hash = Sha256::new();
+    if let Some(build_dir) = &build_dir {
+        hash_dir(&mut context_hash, build_dir, build_dir)?;
+    }
+    // Only the names of passed through build args and secrets, so their values stay off the image
+    let mut build_settings: Vec<String> = Vec::new();
+    build_settings.extend(image_config.build_args.iter().map(|arg| format!("build-arg {}", String::from(arg.clone()))));
+    build_settings.extend(image_config.build_target.iter().map(|target| format!("build-target {}", target)));
+    build_settings.extend(image_config.build_secrets.iter().map(|secret| format!("build-secret {}", String::from(secret.clone()))));
+    build_settings.extend(image_config.build_ssh.iter().map(|ssh| format!("build-ssh {}", ssh)));
+    for setting in build_settings.iter() {
+        context_hash.update(setting.as_bytes());
+        context_hash.update([0]);
+    }
+    labels.insert(CONTEXT_HASH_LABEL.to_string(), format!("{:x}", context_hash.finalize()));
+
+    // Builds pull every base image, pinned or not
+    let include_pinned = true;
+    Ok(BuildPlan {
+        image_name: image_name.to_string(),
+        image_with_tag: add_codo_tag(image_name),
+        dockerfile: extended_dockerfile,
+        build_dir,
+        labels,
+        build_args: image_config.build_args.clone(),
+        target: image_config.build_target.clone(),
+        secrets: image_config.build_secrets.clone(),
+        ssh: image_config.build_ssh.clone(),
+        pull: image_config.pull,
+        base_images: base_images(&dockerfile, include_pinned),
+    })
+}
+
+// Get the image and stage name of a FROM instruction
+pub fn from_instruction(line: &str) -> Option<(String, Option<String>)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if !words.first()?.eq_ignore_ascii_case("FROM") {
+        return None;
+    }
+    let words: Vec<&str> = words[1..].iter().filter(|word| !word.starts_with("--")).cloned().collect();
+    let stage = match words.as_slice() {
+        [_, as_word, stage] if as_word.eq_ignore_ascii_case("AS") => Some(stage.to_lowercase()),
+        _ => None,
+    };
+    Some((words.first()?.to_string(), stage))
+}
+
+// Give a base image reference the tag the engine assumes
+pub fn normalize_reference(reference: &str) -> String {
+    // Listing images by a bare repository would match every tag
+    match split_reference(reference) {
+        (_, None) if !reference.contains('@') => format!("{}:latest", reference),
+        _ => reference.to_string(),
+    }
+}
+
+// Check if a FROM instruction names an image that can be pulled, rather than an earlier stage
+pub fn is_pullable(base_image: &str, stages: &[String]) -> bool {
+    // Images named by build args are left to the engine
+    !stages.contains(&base_image.to_lowercase()) && base_image != "scratch" && !base_image.contains('$')
+}
+
+/// Get the images named in FROM instructions, leaving out earlier stages and optionally images pinned by digest
+pub fn base_images(dockerfile: &str, include_pinned: bool) -> Vec<String> {
+    let mut stages: Vec<String> = Vec::new();
+    let mut base_images: Vec<String> = Vec::new();
+    for (base_image, stage) in dockerfile.lines().filter_map(from_instruction) {
+        if is_pullable(&base_image, &stages) && (include_pinned || !base_image.contains('@')) {
+            let base_image = normalize_reference(&base_image);
+            if !base_images.contains(&base_image) {
+                base_images.push(base_image);
+            }
+        }
+        stages.extend(stage);
+    }
+    base_images
+}
+
+// Hash the names and contents of every file under a directory
+fn hash_dir(hash: &mut Sha256, root: &path::Path, dir: &path::Path) -> Result<(), Box<dyn error::Error>> {
+    let mut entries: Vec<path::PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    for entry in entries.iter() {
+        let relative_path = entry.strip_prefix(root)?;
+        let file_type = fs::symlink_metadata(entry)?.file_type();
+        if file_type.is_dir() {
+            hash_dir(hash, root, entry)?;
+        } else if file_type.is_file() {
+            hash.update(relative_path.to_string_lossy().as_bytes());
+            hash.update([0]);
+            hash.update(fs::read(entry)?);
+            hash.update([0]);
+        } else if file_type.is_symlink() {
+            // Links can point at directories or nothing at all, so only where they point is hashed
+            hash.update(relative_path.to_string_lossy().as_bytes());
+            hash.update([1]);
+            hash.update(fs::read_link(entry)?.as_os_str().as_bytes());
+            hash.update([0]);
+        }
+    }
+
+    Ok(())
+}
+
+// Where the Dockerfile of a build is written, with the Xs replaced to make it unique
+pub fn temp_dir_template() -> path::PathBuf {
+    env::temp_dir().join("codo-build-XXXXXX")
+}
+
+/// A private directory for the files of one build, removed when dropped
+pub struct TempDir {
+    path: path::PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> io::Result<TempDir> {
+        // mkdtemp picks a name no one else has and makes the directory only readable by the user
+        let mut template = ffi::CString::new(temp_dir_template().into_os_string().into_vec())?.into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        template.pop();
+        Ok(TempDir { path: path::PathBuf::from(ffi::OsString::from_vec(template)) })
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            error!("Failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+pub fn build_lock_dir() -> Option<path::PathBuf> {
+    let mut build_lock_dir = dirs::cache_dir()?;
+    build_lock_dir.push("codo");
+    build_lock_dir.push("locks");
+    Some(build_lock_dir)
+}
+
+/// Holds the lock on building an image tag until dropped
+pub struct BuildLock {
+    _file: fs::File,
+    // Another build held the lock first, so the image may be up to date now
+    pub waited: bool,
+}
+
+pub fn lock_build(plan: &BuildPlan) -> Result<BuildLock, Box<dyn error::Error>> {
+    let build_lock_dir = match build_lock_dir() {
+        Some(dir) => dir,
+        None => {
+            let err = "Failed to get the cache directory for build locks";
+            return Err(Box::new(codo_error::Error::new(codo_error::ErrorKind::BuildFailure, err)));
+        }
+    };
+    fs::create_dir_all(&build_lock_dir)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(build_lock_dir.join(format!("{}.lock", home::storage_name(&plan.image_with_tag))))?;
+
+    // Only say there's a wait when there is one
+    let fd = file.as_raw_fd();
+    let mut waited = false;
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(Box::new(err));
+        }
+        eprintln!("Waiting for another build of {} to finish", plan.image_with_tag);
+        waited = true;
+        while unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Box::new(err));
+            }
+        }
+    }
+
+    Ok(BuildLock { _file: file, waited })
+}
+
+pub fn build_command(engine: &dyn ContainerEngine, plan: &BuildPlan, temp_dockerfile_dir: &path::Path) -> Vec<String> {
+    let build_dir = plan.build_dir.clone().unwrap_or_else(|| temp_dockerfile_dir.to_path_buf());
+    let temp_dockerfile_path = temp_dockerfile_dir.join("Dockerfile");
 
     // Create the build command
     let temp_dockerfile_path = temp_dockerfile_path
         .into_os_string().into_string().expect("Failed to convert temp Dockerfile path to string");
     let build_dir = build_dir
         .into_os_string().into_string().expect("Failed to convert build directory to string");
-    let build_command: Vec<String> = vec![
-        "sudo".to_string(),
-        "docker".to_string(),
-        "build".to_string(),
+    let build_args = [
+        "build",
         // Add the image tag
-        "-t".to_string(),
-        image_with_tag,
+        "-t",
+        &plan.image_with_tag,
         // Add the path to the Dockerfile
-        "-f".to_string(),
-        temp_dockerfile_path,
-        // Pull the latest image
-        "--pull".to_string(),
-        // Give the build directory
-        build_dir
+        "-f",
+        &temp_dockerfile_path,
     ];
+    let mut build_command: Vec<String> = if plan.needs_buildkit() {
+        engine.buildkit_command(&build_args)
+    } else {
+        engine.command(&build_args)
+    };
+    if let Some(target) = &plan.target {
+        build_command.push("--target".to_string());
+        build_command.push(target.to_owned());
+    }
+    // Build args passed through from the host are left out when they aren't set
+    for build_arg in plan.build_args.iter().filter_map(|arg| arg.env_arg()) {
+        build_command.push("--build-arg".to_string());
+        build_command.push(build_arg);
+    }
+    for secret in plan.secrets.iter() {
+        build_command.push("--secret".to_string());
+        build_command.push(secret.secret_arg(temp_dockerfile_dir));
+    }
+    for ssh in plan.ssh.iter() {
+        build_command.push("--ssh".to_string());
+        build_command.push(config::ssh_arg(ssh));
+    }
+    let mut labels: Vec<(&str, String)> = vec![
+        (MANAGED_LABEL, "true".to_string()),
+        (IMAGE_LABEL, plan.image_name.to_owned()),
+    ];
+    if let Some(user) = current_user() {
+        labels.push((USER_LABEL, user.name));
+    }
+    labels.extend(plan.labels.iter().map(|(key, value)| (key.as_str(), value.to_owned())));
+    for (key, value) in labels.iter() {
+        build_command.push("--label".to_string());
+        build_command.push(format!("{}={}", key, value));
+    }
+    // Give the build directory
+    build_command.push(build_dir);
+    build_command
+}
+
+// Get the commands that pull the base images, with what decides if they're run
+pub fn pull_commands(engine: &dyn ContainerEngine, plan: &BuildPlan) -> Vec<(String, Vec<String>)> {
+    let heading = match plan.pull {
+        PullPolicy::Always => "Pull base image before building, using the cached copy if the registry is unreachable",
+        PullPolicy::Missing => "Pull base image before building, run if it isn't present",
+        PullPolicy::Never => return Vec::new(),
+    };
+    plan.base_images.iter()
+        .map(|base_image| (heading.to_string(), engine.command(&["pull", base_image])))
+        .collect()
+}
+
+// Make sure the base images are present as the pull policy asks, and say which ones the build uses
+fn pull_base_images(engine: &dyn ContainerEngine, plan: &BuildPlan) -> Result<(), Box<dyn error::Error>> {
+    let build_failure = |err: String| -> Box<dyn error::Error> {
+        Box::new(codo_error::Error::new(codo_error::ErrorKind::BuildFailure, &err))
+    };
+
+    for base_image in plan.base_images.iter() {
+        let cached = image_info(engine, base_image)?.is_some();
+        let source = match (plan.pull, cached) {
+            (PullPolicy::Never, false) => {
+                return Err(build_failure(format!("Base image {} isn't present and the pull policy is never", base_image)));
+            },
+            (PullPolicy::Never, true) | (PullPolicy::Missing, true) => "cached",
+            (_, cached) => {
+                let inherit_io = true;
+                match run_command(&engine.command(&["pull", base_image]), inherit_io) {
+                    Ok(_) => "pulled",
+                    // Fall back to the missing policy so builds work offline
+                    Err(err) if cached => {
+                        debug!("Failed to pull {}: {}", base_image, err);
+                        eprintln!("Failed to pull {}, the registry may be unreachable. Using the cached copy.", base_image);
+                        "cached, pull failed"
+                    },
+                    Err(err) => return Err(build_failure(format!("Failed to pull base image {}: {}", base_image, err))),
+                }
+            }
+        };
+
+        // Images built locally have no digest, so fall back to their id
+        match image_info(engine, base_image)? {
+            Some(info) => eprintln!("Base image {} is {} ({})", base_image, info.digest.unwrap_or(info.id), source),
+            None => eprintln!("Base image {} ({})", base_image, source),
+        };
+    }
+
+    Ok(())
+}
+
+pub fn build(engine: &dyn ContainerEngine, plan: &BuildPlan) -> Result<(), Box<dyn error::Error>> {
+    let build_lock = lock_build(plan)?;
+    build_locked(engine, plan, &build_lock)
+}
+
+// Write env secrets to files only the user can read, and check the agent is there for SSH
+fn write_secrets(plan: &BuildPlan, temp_dir: &path::Path) -> Result<(), Box<dyn error::Error>> {
+    let build_failure = |err: String| -> Box<dyn error::Error> {
+        Box::new(codo_error::Error::new(codo_error::ErrorKind::BuildFailure, &err))
+    };
+
+    for secret in plan.secrets.iter() {
+        let name = match &secret.env {
+            Some(name) => name,
+            None => continue,
+        };
+        let value = match env::var_os(name) {
+            Some(value) => value,
+            None => return Err(build_failure(format!("Build secret {} needs {} to be set", secret.id, name))),
+        };
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(temp_dir.join(secret.file_name()))?;
+        file.write_all(value.as_bytes())?;
+    }
+
+    if let Some(ssh) = plan.ssh.iter().find(|ssh| !ssh.contains('=')) {
+        if env::var_os(config::SSH_AUTH_SOCK).is_none() {
+            return Err(build_failure(format!("build-ssh {} needs an SSH agent, but {} isn't set", ssh, config::SSH_AUTH_SOCK)));
+        }
+    }
+    Ok(())
+}
+
+// Build an image while holding the lock on its tag
+pub fn build_locked(engine: &dyn ContainerEngine, plan: &BuildPlan, _build_lock: &BuildLock) -> Result<(), Box<dyn error::Error>> {
+    pull_base_images(engine, plan)?;
+    debug!("Building Dockerfile: \n {}", plan.dockerfile);
+
+    // Write the final dockerfile where no other build can touch it
+    let temp_dir = TempDir::new()?;
+    fs::write(temp_dir.path().join("Dockerfile"), &plan.dockerfile)?;
+    write_secrets(plan, temp_dir.path())?;
+    let build_command = build_command(engine, plan, temp_dir.path());
 
     // Run the build command
     let inherit_io = true;
-    run_command(&build_command, inherit_io)?;
+    if let Err(err) = run_command(&build_command, inherit_io) {
+        let err = format!("Failed to build {}: {}", plan.image_name, err);
+        let err = codo_error::Error::new(codo_error::ErrorKind::BuildFailure, &err);
+        return Err(Box::new(err));
+    }
 
-    return Ok(());
+    Ok(())
 }
 
-pub fn images_info() -> Result<HashMap<String, HashMap<String, String>>, Box<dyn error::Error>> {
-    // Run the command
-    let images_info_command: Vec<String> = vec!["sudo".to_string(), "docker".to_string(), "images".to_string()];
-    let inherit_io = false;
-    let images_info = run_command(&images_info_command, inherit_io)?;
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub id: String,
+    pub repository: Option<String>,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+    pub created: String,
+    pub size: u64,
+    pub labels: HashMap<String, String>,
+}
 
-    // Get the header line
-    let images_info = String::from_utf8(images_info.stdout)?;
-    let mut images_info = (&images_info).lines();
-    let images_header: &str = match images_info.next() {
-        Some(header) => header,
-        None => {
-            let err = format!("Failed to get header from {:?}", images_info_command);
-            let err = codo_error::Error::new(codo_error::ErrorKind::ContainerEngineFailure, &err);
-            return Err(Box::new(err));
+// Fields shared by `docker image inspect` and `podman image inspect`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectOutput {
+    id: String,
+    repo_tags: Option<Vec<String>>,
+    repo_digests: Option<Vec<String>>,
+    created: String,
+    size: u64,
+    config: Option<InspectConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectConfig {
+    labels: Option<HashMap<String, String>>,
+}
+
+impl ImageInfo {
+    pub fn is_codo_build(&self) -> bool {
+        self.tag.as_deref().is_some_and(is_codo_tag)
+    }
+
+    // Also match earlier builds of the current user that are only known by their labels
+    pub fn is_codo_image(&self) -> bool {
+        if self.is_codo_build() {
+            return true;
         }
-    };
-    let images_header: Vec<String> = split_columns(images_header);
-    debug!("Image info header: {:?}", images_header);
-
-    // Turn into a vector of hash maps
-    let images_info: Vec<HashMap<String, String>> = images_info.map(|s: &str| -> HashMap<String, String> {
-        let mut m: HashMap<String, String> = HashMap::new();
-        for (k, v) in images_header.iter().zip(split_columns(s).iter()) {
-            m.insert(k.to_owned(), v.to_owned());
+        let label = |key: &str| self.labels.get(key).map(|value| value.as_str());
+        self.tag.is_none()
+            && label(MANAGED_LABEL) == Some("true")
+            && label(USER_LABEL).is_some_and(|user| current_user().is_some_and(|current| current.name == user))
+    }
+
+    // Name of the image this was built for, with the tag add_codo_tag assumes
+    pub fn image_name(&self) -> Option<String> {
+        if let Some(image_name) = self.labels.get(IMAGE_LABEL) {
+            if image_name.contains(':') {
+                return Some(image_name.to_owned());
+            }
+            return Some(format!("{}:latest", image_name));
+        }
+        let suffix = format!("-{}", codo_tag_suffix());
+        let tag = self.tag.as_ref()?.strip_suffix(&suffix)?;
+        Some(format!("{}:{}", self.repository.as_ref()?, tag))
+    }
+
+    // Reference to remove this entry by, the tag if it has one
+    pub fn reference(&self) -> String {
+        match (&self.repository, &self.tag) {
+            (Some(repository), Some(tag)) => format!("{}:{}", repository, tag),
+            _ => self.id.to_owned(),
+        }
+    }
+
+    fn from_inspect(inspect: &InspectOutput, reference: Option<&str>) -> ImageInfo {
+        let (repository, tag) = match reference {
+            Some(reference) => {
+                let (repository, tag) = split_reference(reference);
+                (Some(repository), tag)
+            },
+            None => (None, None)
+        };
+        // The same image can be pulled from several registries, each with its own digest
+        let digest = repository.as_ref().and_then(|repository| {
+            let repository = qualified_repository(repository);
+            inspect.repo_digests.iter().flatten()
+                .filter_map(|digest| digest.split_once('@'))
+                .find(|(digest_repository, _)| qualified_repository(digest_repository) == repository)
+                .map(|(_, digest)| digest.to_string())
+        });
+        let labels = inspect.config.as_ref()
+            .and_then(|config| config.labels.clone())
+            .unwrap_or_default();
+
+        ImageInfo {
+            id: inspect.id.to_owned(),
+            repository,
+            tag,
+            digest,
+            created: inspect.created.to_owned(),
+            size: inspect.size,
+            labels,
+        }
+    }
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let none = "<none>".to_string();
+        write!(f, "{}:{} {} digest={} created={} size={} labels={:?}",
+            self.repository.as_ref().unwrap_or(&none),
+            self.tag.as_ref().unwrap_or(&none),
+            self.id,
+            self.digest.as_ref().unwrap_or(&none),
+            self.created,
+            self.size,
+            self.labels)
+    }
+}
+
+pub fn images_info(engine: &dyn ContainerEngine) -> Result<Vec<ImageInfo>, Box<dyn error::Error>> {
+    // Get the ids of all the images
+    let image_ids = image_ids(engine, None)?;
+    if image_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Get one entry for each tag of each image
+    let mut images_info: Vec<ImageInfo> = Vec::new();
+    for inspect in inspect_images(engine, &image_ids)?.iter() {
+        match &inspect.repo_tags {
+            Some(repo_tags) if !repo_tags.is_empty() => {
+                for repo_tag in repo_tags.iter() {
+                    images_info.push(ImageInfo::from_inspect(inspect, Some(repo_tag)));
+                }
+            },
+            _ => images_info.push(ImageInfo::from_inspect(inspect, None)),
         }
-        return m;
-    }).collect();
-    debug!("Image info vector of hashmaps: {:?}", images_info);
-
-    // Create a map of the image info
-    let mut images_info_map: HashMap<String, HashMap<String, String>> = HashMap::new();
-    for image in images_info.iter() {
-        let key = format!("{}:{}", image["REPOSITORY"], image["TAG"]);
-        images_info_map.insert(key, image.to_owned());
-    }
-    /*
-    // Sort out the images by repository and tag
-    let mut repositories: HashMap<String, HashMap<String, HashMap<String, String>>> = HashMap::new();
-    for image in images_info.iter() {
-        // Check if there is a map for this repository
-        if !repositories.contains_key(&image["REPOSITORY"]) {
-            let new_repository = HashMap::new();
-            repositories.insert(image["REPOSITORY"].to_owned(), new_repository);
+    }
+    debug!("Image info: {:?}", images_info);
+
+    Ok(images_info)
+}
+
+pub fn image_info(engine: &dyn ContainerEngine, reference: &str) -> Result<Option<ImageInfo>, Box<dyn error::Error>> {
+    // Check if the image is present
+    if image_ids(engine, Some(reference))?.is_empty() {
+        debug!("Image {} not found", reference);
+        return Ok(None);
+    }
+
+    // Inspect the image
+    let inspect = inspect_images(engine, &[reference.to_string()])?;
+    let image_info = inspect.first()
+        .map(|inspect| ImageInfo::from_inspect(inspect, Some(reference)));
+
+    Ok(image_info)
+}
+
+fn image_ids(engine: &dyn ContainerEngine, reference: Option<&str>) -> Result<Vec<String>, Box<dyn error::Error>> {
+    // List the full image ids
+    let mut image_ids_command = engine.command(&["images", "--quiet", "--no-trunc"]);
+    if let Some(reference) = reference {
+        image_ids_command.push(reference.to_string());
+    }
+    let inherit_io = false;
+    let image_ids = run_command(&image_ids_command, inherit_io)?;
+
+    // The same id is listed once for each tag
+    let mut result: Vec<String> = Vec::new();
+    for id in String::from_utf8(image_ids.stdout)?.lines().map(|s| s.trim()) {
+        if !id.is_empty() && !result.iter().any(|s| s == id) {
+            result.push(id.to_string());
         }
-        repositories.get_mut(&image["REPOSITORY"])
-            .unwrap()
-            .insert(image["TAG"].to_owned(), image.to_owned());
     }
-    */
 
-    // Return the image info
-    return Ok(images_info_map);
+    Ok(result)
 }
 
-pub fn run_command(command: &Vec<String>, inherit_io: bool) -> Result<process::Output, Box<dyn error::Error>> {
+fn inspect_images(engine: &dyn ContainerEngine, references: &[String]) -> Result<Vec<InspectOutput>, Box<dyn error::Error>> {
+    let mut inspect_command = engine.command(&["image", "inspect"]);
+    inspect_command.extend(references.iter().cloned());
+    let inherit_io = false;
+    let inspect = run_command(&inspect_command, inherit_io)?;
+
+    match serde_json::from_slice(&inspect.stdout) {
+        Ok(inspect) => Ok(inspect),
+        Err(err) => {
+            let err = format!("Failed to parse output of {:?}: {}", inspect_command, err);
+            let err = codo_error::Error::new(codo_error::ErrorKind::ContainerEngineFailure, &err);
+            Err(Box::new(err))
+        }
+    }
+}
+
+/// Split an image reference into its repository and tag
+pub fn split_reference(reference: &str) -> (String, Option<String>) {
+    // A colon before the last slash belongs to a registry port
+    let name_start = reference.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match reference[name_start..].rfind(':') {
+        Some(i) => {
+            let i = name_start + i;
+            (reference[..i].to_string(), Some(reference[i + 1..].to_string()))
+        },
+        None => (reference.to_string(), None)
+    }
+}
+
+// Give a repository the registry and namespace the engines assume for short names
+fn qualified_repository(repository: &str) -> String {
+    let (registry, path) = match repository.split_once('/') {
+        Some((first, rest)) if first.contains('.') || first.contains(':') || first == "localhost" => (first, rest),
+        _ => (DOCKER_HUB, repository),
+    };
+    let registry = if registry == "index.docker.io" { DOCKER_HUB } else { registry };
+    if registry == DOCKER_HUB && !path.contains('/') {
+        format!("{}/library/{}", registry, path)
+    } else {
+        format!("{}/{}", registry, path)
+    }
+}
+
+pub fn run_command(command: &[String], inherit_io: bool) -> Result<process::Output, Box<dyn error::Error>> {
     // Run the build command
-    let output: process::Output;
-    if inherit_io {
-        output = Command::new(&command[0])
+    let output: process::Output = if inherit_io {
+        Command::new(&command[0])
             .args(&command[1..])
             .stdin(Stdio::inherit())
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
-            .output()?;
+            .output()?
     } else {
-        output = Command::new(&command[0])
+        Command::new(&command[0])
             .args(&command[1..])
-            .output()?;
-    }
+            .output()?
+    };
 
     // Check if the build was a success
     if !output.status.success() {
@@ -255,17 +875,209 @@ pub fn run_command(command: &Vec<String>, inherit_io: bool) -> Result<process::O
         return Err(Box::new(err));
     }
 
-    return Ok(output);
+    Ok(output)
 }
 
-fn split_columns(columns: &str) -> Vec<String> {
-    let mut result: Vec<String> = Vec::new();
-    for column in columns.split("   ") {
-        if column != "" {
-            result.push(column.trim().to_string());
+// Quote a command so it can be pasted into a POSIX shell
+pub fn quote_command(command: &[String]) -> String {
+    command.iter()
+        .map(|arg| {
+            let safe = !arg.is_empty() && arg.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c));
+            if safe {
+                arg.to_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Signals passed on to the engine so they reach the container
+const FORWARDED_SIGNALS: [libc::c_int; 4] = [SIGINT, SIGTERM, SIGHUP, SIGWINCH];
+
+/// How a command started by `run_interactive` ended
+pub struct RunOutcome {
+    pub status: process::ExitStatus,
+    pub interrupted: bool,
+}
+
+impl RunOutcome {
+    /// Check if the command was stopped rather than exiting by itself
+    pub fn is_abnormal(&self) -> bool {
+        self.interrupted || self.status.signal().is_some()
+    }
+}
+
+/// How the input and output of a command started by `run_interactive` are connected
+pub enum Streams {
+    Inherit,
+    // Rewrite container paths in stdout and stderr
+    RewriteOutput(paths::PathMap),
+    // Pass language server messages between stdin and the file, rewriting their paths
+    Messages(Option<paths::PathMap>, fs::File),
+}
+
+pub fn run_interactive(command: &[String], streams: Streams) -> Result<RunOutcome, Box<dyn error::Error>> {
+    // Catch signals before starting the command so none of them kill codo first
+    let mut signals = SignalsInfo::<WithOrigin>::new(FORWARDED_SIGNALS)?;
+    let signals_handle = signals.handle();
+
+    let mut child_command = Command::new(&command[0]);
+    child_command.args(&command[1..]);
+    match streams {
+        Streams::Inherit => (),
+        Streams::RewriteOutput(_) => {
+            child_command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        },
+        Streams::Messages(_, _) => {
+            child_command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        },
+    };
+    let mut child = match child_command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            let err = format!("Failed to start {:?}: {}", command, err);
+            let err = codo_error::Error::new(codo_error::ErrorKind::ContainerEngineFailure, &err);
+            return Err(Box::new(err));
+        }
+    };
+    let child_pid = child.id() as libc::pid_t;
+
+    // Pass the output through with container paths changed to host paths
+    let mut copiers: Vec<thread::JoinHandle<io::Result<()>>> = Vec::new();
+    match streams {
+        Streams::Inherit => (),
+        Streams::RewriteOutput(path_map) => {
+            if let Some(stdout) = child.stdout.take() {
+                let rewriter = path_map.to_host_rewriter();
+                copiers.push(thread::spawn(move || paths::copy_rewritten(stdout, io::stdout(), rewriter)));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                let rewriter = path_map.to_host_rewriter();
+                copiers.push(thread::spawn(move || paths::copy_rewritten(stderr, io::stderr(), rewriter)));
+            }
+        },
+        Streams::Messages(path_map, output) => {
+            // Input is left to the end of the editor's stream, so it isn't waited for
+            if let Some(stdin) = child.stdin.take() {
+                let path_map = path_map.clone();
+                thread::spawn(move || lsp::copy_messages(io::stdin(), stdin, path_map, lsp::Direction::ToContainer));
+            }
+            if let Some(stdout) = child.stdout.take() {
+                copiers.push(thread::spawn(move || lsp::copy_messages(stdout, output, path_map, lsp::Direction::ToHost)));
+            }
+        },
+    };
+
+    let forwarder = thread::spawn(move || {
+        let mut interrupted = false;
+        for origin in signals.forever() {
+            if origin.signal != SIGWINCH {
+                interrupted = true;
+            }
+            // Signals from the terminal already reach the whole foreground process group
+            match origin.process {
+                Some(sender) if sender.pid != child_pid => {
+                    debug!("Forwarding signal {} from {} to {}", origin.signal, sender.pid, child_pid);
+                    unsafe { libc::kill(child_pid, origin.signal) };
+                },
+                _ => debug!("Received signal {}", origin.signal),
+            }
+        }
+        interrupted
+    });
+
+    // Leave the exit status to the caller
+    let status = child.wait();
+    for copier in copiers {
+        // A closed pipe means the reader has all the output it wants
+        if let Ok(Err(err)) = copier.join() {
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                error!("Failed to copy output: {}", err);
+            }
         }
     }
+    signals_handle.close();
+    let interrupted = forwarder.join().unwrap_or(true);
+    Ok(RunOutcome { status: status?, interrupted })
+}
 
-    return result;
+pub fn status_code(status: &process::ExitStatus) -> i32 {
+    // Follow the shell convention for processes killed by a signal
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => codo_error::EXIT_UNKNOWN,
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspect(repo_digests: &[&str]) -> InspectOutput {
+        InspectOutput {
+            id: "sha256:1111".to_string(),
+            repo_tags: None,
+            repo_digests: Some(repo_digests.iter().map(|digest| digest.to_string()).collect()),
+            created: String::new(),
+            size: 0,
+            config: None,
+        }
+    }
+
+    #[test]
+    fn digest_belongs_to_inspected_repository() {
+        let inspect = inspect(&["registry.fedoraproject.org/fedora@sha256:aaaa", "fedora@sha256:bbbb", "docker.io/library/alpine@sha256:cccc"]);
+        let digest = |reference| ImageInfo::from_inspect(&inspect, Some(reference)).digest;
+        assert_eq!(digest("fedora:latest").as_deref(), Some("sha256:bbbb"));
+        assert_eq!(digest("docker.io/library/fedora:39").as_deref(), Some("sha256:bbbb"));
+        assert_eq!(digest("registry.fedoraproject.org/fedora:latest").as_deref(), Some("sha256:aaaa"));
+        assert_eq!(digest("alpine:3").as_deref(), Some("sha256:cccc"));
+        assert_eq!(digest("localhost:5000/fedora:latest"), None);
+    }
+
+    #[test]
+    fn quote_command_for_shell() {
+        let command: Vec<String> = ["docker", "run", "-e", "GREETING=hello world", "it's", "~/src", "", "$HOME", "--label=a.b/c@d:1,2%+"]
+            .iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(quote_command(&command),
+            r#"docker run -e 'GREETING=hello world' 'it'\''s' '~/src' '' '$HOME' --label=a.b/c@d:1,2%+"#);
+    }
+
+    #[test]
+    fn split_reference_with_registry_port() {
+        assert_eq!(split_reference("fedora:39"), ("fedora".to_string(), Some("39".to_string())));
+        assert_eq!(split_reference("fedora"), ("fedora".to_string(), None));
+        assert_eq!(split_reference("localhost:5000/tools"), ("localhost:5000/tools".to_string(), None));
+        assert_eq!(split_reference("localhost:5000/team/tools:2-codo-root"),
+            ("localhost:5000/team/tools".to_string(), Some("2-codo-root".to_string())));
+    }
+
+    #[test]
+    fn untagged_entry_is_known_by_its_id() {
+        let output = r#"[{"Id":"sha256:2222","RepoTags":[],"RepoDigests":null,"Created":"2021-06-01T10:00:00Z","Size":42,
+            "Config":{"Labels":null}}]"#;
+        let inspect: Vec<InspectOutput> = serde_json::from_str(output).expect("inspect output didn't parse");
+        let info = ImageInfo::from_inspect(&inspect[0], None);
+        assert_eq!(info.reference(), "sha256:2222");
+        assert!(info.to_string().starts_with("<none>:<none> sha256:2222 digest=<none>"));
+        assert!(info.labels.is_empty());
+        assert_eq!((info.repository, info.tag, info.digest), (None, None, None));
+    }
+
+    #[test]
+    fn tagged_entry_keeps_spaces_in_labels() {
+        let output = r#"[{"Id":"sha256:3333","RepoTags":["localhost:5000/tools:2-codo-root"],"RepoDigests":[],
+            "Created":"2021-06-01T10:00:00Z","Size":7,"Config":{"Labels":{"codo.image":"tools:2","note":"a   b"}}}]"#;
+        let inspect: Vec<InspectOutput> = serde_json::from_str(output).expect("inspect output didn't parse");
+        let info = ImageInfo::from_inspect(&inspect[0], Some("localhost:5000/tools:2-codo-root"));
+        assert_eq!(info.repository.as_deref(), Some("localhost:5000/tools"));
+        assert_eq!(info.tag.as_deref(), Some("2-codo-root"));
+        assert_eq!(info.labels.get("note").map(String::as_str), Some("a   b"));
+        assert_eq!(info.image_name().as_deref(), Some("tools:2"));
+        assert_eq!(info.reference(), "localhost:5000/tools:2-codo-root");
+    }
+}
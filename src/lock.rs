@@ -0,0 +1,321 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::collections::BTreeMap;
+use std::error;
+use std::fs;
+use std::path;
+
+// Crate
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Internal
+use crate::codo_error::{self, fail};
+use crate::config;
+use crate::engine::ContainerEngine;
+use crate::image;
+
+pub const LOCK_FILE: &str = "codo.lock";
+
+const LOCK_HEADER: &str = "# Written by `codo update` and `codo lock`. Commit it so everyone builds from the same base images.\n";
+
+/// Base image digests pinned for the images used in a project
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Lock {
+    pub images: BTreeMap<String, ImageLock>,
+}
+
+/// What an image was locked to
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageLock {
+    // Hash of the Dockerfile before codo adds the user, which differs between users
+    pub dockerfile_hash: String,
+    // Digest of each base image by the reference in the Dockerfile
+    pub base_images: BTreeMap<String, String>,
+}
+
+/// Get the lock file of the project the working directory is in
+pub fn lock_file() -> Option<path::PathBuf> {
+    config::project_dir().map(|dir| dir.join(LOCK_FILE))
+}
+
+fn invalid_lock(err: String) -> Box<dyn error::Error> {
+    Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err))
+}
+
+/// Read the project's lock file, if it has one
+pub fn read() -> Result<Option<Lock>, Box<dyn error::Error>> {
+    let lock_file = match lock_file() {
+        Some(file) if file.is_file() => file,
+        _ => return Ok(None),
+    };
+    let contents = fs::read_to_string(&lock_file)?;
+    match serde_yaml::from_str(&contents) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(err) => Err(invalid_lock(format!("Failed to parse {:?}: {}", lock_file, err))),
+    }
+}
+
+fn write(lock_file: &path::Path, lock: &Lock) -> Result<(), Box<dyn error::Error>> {
+    let contents = format!("{}{}", LOCK_HEADER, serde_yaml::to_string(lock)?);
+    fs::write(lock_file, contents)?;
+    Ok(())
+}
+
+pub fn dockerfile_hash(dockerfile: &str) -> String {
+    format!("{:x}", Sha256::digest(dockerfile.as_bytes()))
+}
+
+/// Replace the base images of a Dockerfile with the digests in the project's lock file
+pub fn pin_dockerfile(image_name: &str, dockerfile: &str) -> Result<String, Box<dyn error::Error>> {
+    let image_lock = match read()?.and_then(|mut lock| lock.images.remove(image_name)) {
+        Some(image_lock) => image_lock,
+        None => return Ok(dockerfile.to_string()),
+    };
+    if image_lock.dockerfile_hash != dockerfile_hash(dockerfile) {
+        eprintln!("The Dockerfile of {} changed since it was locked, so its base images aren't pinned. Run `codo update {}`.",
+            image_name, image_name);
+        return Ok(dockerfile.to_string());
+    }
+    Ok(pin(dockerfile, &image_lock))
+}
+
+// Pin the base images of a Dockerfile that the lock has a digest for
+fn pin(dockerfile: &str, image_lock: &ImageLock) -> String {
+    let mut stages: Vec<String> = Vec::new();
+    let mut pinned: Vec<String> = Vec::new();
+    for line in dockerfile.lines() {
+        let (base_image, stage) = match image::from_instruction(line) {
+            Some(from) => from,
+            None => {
+                pinned.push(line.to_string());
+                continue;
+            }
+        };
+        let reference = image::normalize_reference(&base_image);
+        let digest = image_lock.base_images.get(&reference).filter(|_| image::is_pullable(&base_image, &stages));
+        match digest {
+            Some(digest) => {
+                // Keep the options and stage name around the image
+                let (repository, _) = image::split_reference(&reference);
+                let pinned_image = format!("{}@{}", repository, digest);
+                let words: Vec<String> = line.split_whitespace()
+                    .map(|word| if word == base_image { pinned_image.to_owned() } else { word.to_string() })
+                    .collect();
+                debug!("Pinned {} to {}", base_image, pinned_image);
+                pinned.push(words.join(" "));
+            },
+            None => pinned.push(line.to_string()),
+        }
+        stages.extend(stage);
+    }
+
+    let mut pinned = pinned.join("\n");
+    if dockerfile.ends_with('\n') {
+        pinned.push('\n');
+    }
+    pinned
+}
+
+// Resolve the digests of an image's base images, pulling them first when updating
+fn lock_image(engine: &dyn ContainerEngine, image_name: &str, update: bool) -> Result<ImageLock, Box<dyn error::Error>> {
+    let (dockerfile, _) = image::read_dockerfile(image_name)?;
+    let mut base_images: BTreeMap<String, String> = BTreeMap::new();
+    // Images already pinned in the Dockerfile don't need the lock file
+    let include_pinned = false;
+    for base_image in image::base_images(&dockerfile, include_pinned) {
+        let cached = image::image_info(engine, &base_image)?.is_some();
+        if update || !cached {
+            let inherit_io = true;
+            image::run_command(&engine.command(&["pull", &base_image]), inherit_io)?;
+        }
+
+        // Only images from a registry have a digest to pin
+        match image::image_info(engine, &base_image)?.and_then(|info| info.digest) {
+            Some(digest) => {
+                base_images.insert(base_image, digest);
+            },
+            None => eprintln!("{} has no registry digest, so it can't be pinned", base_image),
+        };
+    }
+    Ok(ImageLock { dockerfile_hash: dockerfile_hash(&dockerfile), base_images })
+}
+
+// Images to lock when none are given: the ones already locked, or the default image
+fn images_to_lock(codo_config: &config::Config, lock: &Lock, image_names: &[&str]) -> Vec<String> {
+    if !image_names.is_empty() {
+        image_names.iter().map(|name| name.to_string()).collect()
+    } else if !lock.images.is_empty() {
+        lock.images.keys().cloned().collect()
+    } else {
+        vec![codo_config.default_image.to_owned()]
+    }
+}
+
+/// Write the digests of the base images to the lock file, pulling the newest ones when updating
+pub fn lock(codo_config: &config::Config, engine: &dyn ContainerEngine, image_names: &[&str], update: bool) -> i32 {
+    let lock_file = match lock_file() {
+        Some(file) => file,
+        None => {
+            let err = invalid_lock("Failed to find the project directory for the lock file".to_string());
+            return fail("Failed to lock base images", err);
+        }
+    };
+    let mut lock = match read() {
+        Ok(lock) => lock.unwrap_or_default(),
+        Err(err) => return fail("Failed to read lock file", err)
+    };
+
+    for image_name in images_to_lock(codo_config, &lock, image_names) {
+        let image_lock = match lock_image(engine, &image_name, update) {
+            Ok(image_lock) => image_lock,
+            Err(err) => return fail(&format!("Failed to lock base images of {}", image_name), err)
+        };
+
+        // Say what changed
+        let old_lock = lock.images.get(&image_name);
+        for (base_image, digest) in image_lock.base_images.iter() {
+            match old_lock.and_then(|old_lock| old_lock.base_images.get(base_image)) {
+                Some(old_digest) if old_digest == digest => println!("{}: {} {} (unchanged)", image_name, base_image, digest),
+                Some(old_digest) => println!("{}: {} {} -> {}", image_name, base_image, old_digest, digest),
+                None => println!("{}: {} {}", image_name, base_image, digest),
+            }
+        }
+        lock.images.insert(image_name, image_lock);
+    }
+
+    match write(&lock_file, &lock) {
+        Ok(_) => {
+            println!("Wrote {}", lock_file.display());
+            codo_error::EXIT_SUCCESS
+        },
+        Err(err) => fail("Failed to write lock file", err)
+    }
+}
+
+/// Check that the lock file pins every base image of the current Dockerfiles
+pub fn check(codo_config: &config::Config, image_names: &[&str]) -> i32 {
+    let lock_file = lock_file().unwrap_or_else(|| path::PathBuf::from(LOCK_FILE));
+    let lock = match read() {
+        Ok(Some(lock)) => lock,
+        Ok(None) => {
+            let err = invalid_lock(format!("{} doesn't exist. Run `codo lock` to create it.", lock_file.display()));
+            return fail("Failed to check lock file", err);
+        },
+        Err(err) => return fail("Failed to read lock file", err)
+    };
+
+    let mut problems: Vec<String> = Vec::new();
+    for image_name in images_to_lock(codo_config, &lock, image_names) {
+        let dockerfile = match image::read_dockerfile(&image_name) {
+            Ok((dockerfile, _)) => dockerfile,
+            Err(err) => return fail(&format!("Failed to read the Dockerfile of {}", image_name), err)
+        };
+        let image_lock = match lock.images.get(&image_name) {
+            Some(image_lock) => image_lock,
+            None => {
+                problems.push(format!("{} isn't locked", image_name));
+                continue;
+            }
+        };
+        if image_lock.dockerfile_hash != dockerfile_hash(&dockerfile) {
+            problems.push(format!("the Dockerfile of {} changed since it was locked", image_name));
+        }
+        let include_pinned = false;
+        for base_image in image::base_images(&dockerfile, include_pinned) {
+            if !image_lock.base_images.contains_key(&base_image) {
+                problems.push(format!("{} in the Dockerfile of {} isn't pinned", base_image, image_name));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{} is up to date", lock_file.display());
+        return codo_error::EXIT_SUCCESS;
+    }
+    for problem in problems.iter() {
+        println!("{}: {}", lock_file.display(), problem);
+    }
+    let err = invalid_lock(format!("{} is out of date. Run `codo update` to update it.", lock_file.display()));
+    fail("Failed to check lock file", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCKERFILE: &str = "FROM --platform=linux/amd64 rust:1.70 AS builder
+RUN cargo build
+FROM builder AS test
+FROM localhost:5000/tools:2
+FROM fedora
+COPY --from=builder /src/target /usr/local/bin
+FROM docker.io/library/alpine@sha256:aaaa
+FROM scratch
+";
+
+    fn image_lock() -> ImageLock {
+        let base_images = [
+            ("rust:1.70", "sha256:1111"),
+            ("localhost:5000/tools:2", "sha256:2222"),
+            ("fedora:latest", "sha256:3333"),
+            // An earlier stage with the name of an image isn't pinned
+            ("builder:latest", "sha256:4444"),
+        ];
+        ImageLock {
+            dockerfile_hash: dockerfile_hash(DOCKERFILE),
+            base_images: base_images.iter().map(|(image, digest)| (image.to_string(), digest.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn pins_multi_stage_dockerfile() {
+        let expected = "FROM --platform=linux/amd64 rust@sha256:1111 AS builder
+RUN cargo build
+FROM builder AS test
+FROM localhost:5000/tools@sha256:2222
+FROM fedora@sha256:3333
+COPY --from=builder /src/target /usr/local/bin
+FROM docker.io/library/alpine@sha256:aaaa
+FROM scratch
+";
+        assert_eq!(pin(DOCKERFILE, &image_lock()), expected);
+    }
+
+    #[test]
+    fn base_images_leave_out_stages_and_pinned_images() {
+        let include_pinned = false;
+        assert_eq!(image::base_images(DOCKERFILE, include_pinned), vec!["rust:1.70", "localhost:5000/tools:2", "fedora:latest"]);
+        let include_pinned = true;
+        assert_eq!(image::base_images(DOCKERFILE, include_pinned).last().map(String::as_str), Some("docker.io/library/alpine@sha256:aaaa"));
+    }
+}
@@ -0,0 +1,255 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::io::FromRawFd;
+
+// Crate
+use log::debug;
+use serde_json::Value;
+
+// Internal
+use crate::paths::PathMap;
+
+const CONTENT_LENGTH: &str = "content-length";
+
+/// Which way messages are going between the editor and the tool
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToContainer,
+    ToHost,
+}
+
+// Keep stdout for the tool's messages and send anything else codo prints to stderr
+pub fn redirect_stdout() -> io::Result<fs::File> {
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if stdout < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(stdout) })
+}
+
+// Characters left as they are in the path of a file URI
+fn is_uri_path_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~/!$&'()*+,;=:@".contains(&byte)
+}
+
+fn encode_uri_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| if is_uri_path_byte(byte) { (byte as char).to_string() } else { format!("%{:02X}", byte) })
+        .collect()
+}
+
+// Replace a directory at the start of a path, but not a directory that only shares its prefix
+fn replace_dir(value: &str, from: &str, to: &str) -> Option<String> {
+    let rest = value.strip_prefix(from)?;
+    if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') || rest.starts_with('#') {
+        Some(format!("{}{}", to, rest))
+    } else {
+        None
+    }
+}
+
+// Rewrite file URIs and absolute paths anywhere in a message
+fn rewrite_value(value: &mut Value, path_map: &PathMap, direction: Direction) {
+    let (from, to) = match direction {
+        Direction::ToContainer => (&path_map.host_dir, &path_map.container_dir),
+        Direction::ToHost => (&path_map.container_dir, &path_map.host_dir),
+    };
+    match value {
+        Value::String(string) => {
+            let from_uri = format!("file://{}", encode_uri_path(from));
+            let to_uri = format!("file://{}", encode_uri_path(to));
+            if let Some(rewritten) = replace_dir(string, &from_uri, &to_uri).or_else(|| replace_dir(string, from, to)) {
+                *string = rewritten;
+            }
+        },
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                rewrite_value(value, path_map, direction);
+            }
+        },
+        Value::Object(map) => {
+            // Workspace edits use URIs as keys
+            let entries: Vec<(String, Value)> = std::mem::take(map).into_iter().collect();
+            for (key, mut value) in entries {
+                let mut key = Value::String(key);
+                rewrite_value(&mut key, path_map, direction);
+                rewrite_value(&mut value, path_map, direction);
+                if let Value::String(key) = key {
+                    map.insert(key, value);
+                }
+            }
+        },
+        _ => (),
+    }
+}
+
+fn rewrite_body(body: Vec<u8>, path_map: &PathMap, direction: Direction) -> Vec<u8> {
+    // Pass anything that isn't JSON through untouched
+    let mut message: Value = match serde_json::from_slice(&body) {
+        Ok(message) => message,
+        Err(err) => {
+            debug!("Not rewriting a message that isn't JSON: {}", err);
+            return body;
+        }
+    };
+    rewrite_value(&mut message, path_map, direction);
+    serde_json::to_vec(&message).unwrap_or(body)
+}
+
+/// Copy Content-Length framed messages, rewriting the paths in each one
+pub fn copy_messages(reader: impl Read, mut writer: impl Write, path_map: Option<PathMap>, direction: Direction) -> io::Result<()> {
+    let path_map = match path_map {
+        Some(path_map) => path_map,
+        None => {
+            // Nothing to rewrite, so the stream doesn't need to be parsed
+            let mut reader = reader;
+            io::copy(&mut reader, &mut writer)?;
+            return writer.flush();
+        }
+    };
+
+    let mut reader = io::BufReader::new(reader);
+    loop {
+        // Read the headers up to the blank line
+        let mut headers: Vec<String> = Vec::new();
+        let mut content_length: Option<usize> = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return writer.flush();
+            }
+            let header = line.trim_end_matches(['\r', '\n']);
+            if header.is_empty() {
+                break;
+            }
+            match header.split_once(':') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case(CONTENT_LENGTH) => {
+                    content_length = value.trim().parse().ok();
+                },
+                _ => headers.push(header.to_string()),
+            }
+        }
+
+        let content_length = match content_length {
+            Some(length) => length,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length header")),
+        };
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let body = rewrite_body(body, &path_map, direction);
+
+        // Write the message with the new length
+        let mut message: Vec<u8> = format!("Content-Length: {}\r\n", body.len()).into_bytes();
+        for header in headers.iter() {
+            message.extend_from_slice(header.as_bytes());
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(&body);
+        writer.write_all(&message)?;
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path_map() -> Option<PathMap> {
+        Some(PathMap { host_dir: "/home/user/my project".to_string(), container_dir: "/codo".to_string() })
+    }
+
+    fn frame(body: &str, extra_header: &str) -> Vec<u8> {
+        format!("Content-Length: {}\r\n{}\r\n{}", body.len(), extra_header, body).into_bytes()
+    }
+
+    // Split a stream back into its headers and bodies, checking every length
+    fn messages(mut stream: &[u8]) -> Vec<(Vec<String>, Vec<u8>)> {
+        let mut messages = Vec::new();
+        while !stream.is_empty() {
+            let end = stream.windows(4).position(|window| window == b"\r\n\r\n").expect("headers don't end");
+            let headers: Vec<String> = String::from_utf8_lossy(&stream[..end]).split("\r\n").map(String::from).collect();
+            let length: usize = headers[0].strip_prefix("Content-Length: ").expect("no Content-Length first").parse().expect("bad length");
+            let body = stream[end + 4..end + 4 + length].to_vec();
+            stream = &stream[end + 4 + length..];
+            messages.push((headers, body));
+        }
+        messages
+    }
+
+    #[test]
+    fn reframes_rewritten_messages() {
+        let first = r#"{"params":{"rootUri":"file:///home/user/my%20project","rootPath":"/home/user/my project/src"}}"#;
+        let second = r#"{"params":{"textDocument":{"uri":"file:///home/user/my%20project2/a.rs"}}}"#;
+        let mut input = frame(first, "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n");
+        input.extend(frame(second, ""));
+
+        let mut output: Vec<u8> = Vec::new();
+        copy_messages(&input[..], &mut output, path_map(), Direction::ToContainer).expect("copy failed");
+        let messages = messages(&output);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0[1], "Content-Type: application/vscode-jsonrpc; charset=utf-8");
+        let body: Value = serde_json::from_slice(&messages[0].1).expect("body isn't JSON");
+        assert_eq!(body, json!({"params": {"rootUri": "file:///codo", "rootPath": "/codo/src"}}));
+        // A directory that only shares the prefix is left alone
+        let body: Value = serde_json::from_slice(&messages[1].1).expect("body isn't JSON");
+        assert_eq!(body, serde_json::from_str::<Value>(second).expect("test body isn't JSON"));
+    }
+
+    #[test]
+    fn rewrites_uri_keys_to_host() {
+        let body = r#"{"result":{"changes":{"file:///codo/src/lib.rs":[{"newText":"x"}]}}}"#;
+        let mut output: Vec<u8> = Vec::new();
+        copy_messages(&frame(body, "")[..], &mut output, path_map(), Direction::ToHost).expect("copy failed");
+        let messages = messages(&output);
+        let body: Value = serde_json::from_slice(&messages[0].1).expect("body isn't JSON");
+        assert_eq!(body, json!({"result": {"changes": {"file:///home/user/my%20project/src/lib.rs": [{"newText": "x"}]}}}));
+    }
+
+    #[test]
+    fn passes_through_bodies_that_arent_json() {
+        let input = frame("not json /codo", "");
+        let mut output: Vec<u8> = Vec::new();
+        copy_messages(&input[..], &mut output, path_map(), Direction::ToHost).expect("copy failed");
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn fails_without_content_length() {
+        let mut output: Vec<u8> = Vec::new();
+        let result = copy_messages(&b"Content-Type: x\r\n\r\n{}"[..], &mut output, path_map(), Direction::ToHost);
+        assert_eq!(result.map_err(|err| err.kind()), Err(io::ErrorKind::InvalidData));
+    }
+}
@@ -27,239 +27,441 @@ OTHER DEALINGS IN THE SOFTWARE.
 
 
 // Standard libraries
-use std;
-use std::collections;
 use std::env;
+use std::process;
 
 // Crates
-use clap;
 use log::error;
 use log::debug;
 
 // Internal
 mod codo_error;
 mod config;
+mod doctor;
+mod engine;
+mod home;
 mod image;
+mod lock;
+mod lsp;
+mod paths;
+mod prune;
+mod run;
+mod session;
+mod shim;
 
-fn arg_value(matches: &clap::ArgMatches, input_command_index: usize, flag_name: &str, default_value: &str) -> String  {
-    // Return the default value if the argument wasn't passed
-    if !arg_passed(matches, input_command_index, flag_name) {
-        return default_value.to_string();
-    }
-
-    // Get the flag value
-    match matches.value_of(flag_name) {
-        Some(value) => value.to_string(),
-        None => {
-            error!("Failed to get value for {}", flag_name);
-            default_value.to_string()
-        }
-    }
-}
-
-fn arg_passed(matches: &clap::ArgMatches, input_command_index: usize, flag_name: &str) -> bool  {
-    // Get the flag index
-    let flag_index = match matches.index_of(flag_name) {
-        Some(index) => index,
-        None => return false
-    };
-    debug!("{} index: {}", flag_name, flag_index);
+use codo_error::fail;
+use engine::ContainerEngine;
+use run::RunOptions;
 
-    // Flag only counts if it comes before the input command
-    return flag_index < input_command_index;
+fn app<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::App::new("codo")
+        .version("0.1")
+        .author("Lyndsey R. M. Dickson")
+        .about("Runs a single command in a container")
+        .usage("codo [OPTIONS] <COMMAND>...\n    codo <SUBCOMMAND>")
+        .after_help(codo_error::EXIT_CODES_HELP)
+        .setting(clap::AppSettings::AllowExternalSubcommands)
+        .setting(clap::AppSettings::VersionlessSubcommands)
+        .args(&run::run_options())
+        .subcommand(clap::SubCommand::with_name("run")
+             .about("Run a command in a container (the default)")
+             .setting(clap::AppSettings::TrailingVarArg)
+             .args(&run::run_options())
+             .arg(clap::Arg::with_name("COMMAND")
+                  .help("Command to be run in the container")
+                  .multiple(true)
+                  .required(false)
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("lsp")
+             .about("Run a language server or other stdio tool, translating the paths in its messages")
+             .setting(clap::AppSettings::TrailingVarArg)
+             .args(&run::image_options())
+             .arg(clap::Arg::with_name("COMMAND")
+                  .help("Tool to be run in the container")
+                  .multiple(true)
+                  .required(true)
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("build")
+             .about("Build a codo image")
+             .arg(run::pull_option())
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Image to build, defaults to default-image")
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("lock")
+             .about("Pin the base images of the project's images to their current digests in codo.lock")
+             .arg(clap::Arg::with_name("check")
+                  .long("check")
+                  .help("Check that codo.lock pins every base image of the current Dockerfiles"))
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Images to lock, defaults to those in codo.lock or default-image")
+                  .multiple(true)
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("update")
+             .about("Pull the newest base images and pin them in codo.lock")
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Images to update, defaults to those in codo.lock or default-image")
+                  .multiple(true)
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("images")
+             .about("List the images built by codo"))
+        .subcommand(clap::SubCommand::with_name("rm")
+             .about("Remove images built by codo")
+             .arg(clap::Arg::with_name("all")
+                  .short("a")
+                  .long("all")
+                  .help("Remove every image built by codo"))
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Images to remove the codo build of")
+                  .multiple(true)
+                  .required_unless("all")
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("prune")
+             .about("Remove old codo builds, showing what goes first")
+             .after_help("Without filters, only builds replaced by a newer build of the same image are removed. \
+                 --older-than and --unused-for narrow down what's removed, --keep protects the newest builds \
+                 and --max-size removes the least recently used builds until the rest fit.")
+             .args(&prune::prune_options()))
+        .subcommand(clap::SubCommand::with_name("home")
+             .about("Show or delete the persistent home of an image")
+             .arg(clap::Arg::with_name("rm")
+                  .long("rm")
+                  .help("Delete the persistent home so the next run starts a fresh one"))
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Image the home belongs to, defaults to default-image")
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("enter")
+             .about("Open a shell in the session of an image for the current project")
+             .args(&run::run_options()))
+        .subcommand(clap::SubCommand::with_name("exec")
+             .about("Run a command in the session of an image for the current project")
+             .setting(clap::AppSettings::TrailingVarArg)
+             .args(&run::run_options())
+             .arg(clap::Arg::with_name("COMMAND")
+                  .help("Command to be run in the session")
+                  .multiple(true)
+                  .required(true)
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("ps")
+             .about("List the codo sessions"))
+        .subcommand(clap::SubCommand::with_name("stop")
+             .about("Stop the session of an image for the current project")
+             .arg(clap::Arg::with_name("all")
+                  .short("a")
+                  .long("all")
+                  .help("Stop every codo session"))
+             .arg(clap::Arg::with_name("rm")
+                  .long("rm")
+                  .help("Remove the session so the next command starts a fresh one"))
+             .arg(clap::Arg::with_name("image")
+                  .short("i")
+                  .long("image")
+                  .help("Image of the session, defaults to default-image")
+                  .takes_value(true)))
+        .subcommand(clap::SubCommand::with_name("export")
+             .about("Install shims that run commands from the host in a container")
+             .arg(clap::Arg::with_name("image")
+                  .short("i")
+                  .long("image")
+                  .help("Image the commands run in, defaults to default-image")
+                  .takes_value(true))
+             .arg(clap::Arg::with_name("list")
+                  .long("list")
+                  .conflicts_with_all(&["rm", "COMMAND"])
+                  .help("List the exported commands"))
+             .arg(clap::Arg::with_name("rm")
+                  .long("rm")
+                  .help("Remove the shims of the commands"))
+             .arg(clap::Arg::with_name("COMMAND")
+                  .help("Commands to export to shim-dir")
+                  .multiple(true)
+                  .required_unless("list")
+                  .index(1)))
+        .subcommand(clap::SubCommand::with_name("doctor")
+             .about("Check the container engine, config and host setup that codo relies on"))
+        .subcommand(clap::SubCommand::with_name("config")
+             .about("Show the config files used and the resulting settings")
+             .arg(clap::Arg::with_name("IMAGE")
+                  .help("Image to include the config of, defaults to default-image")
+                  .index(1)))
 }
 
 fn main() {
     // Start the enviromental logger
     env_logger::init();
 
-    // Get the flags
-    let mut app = clap::App::new("codo")
-        .version("0.1")
-        .author("Lyndsey R. M. Dickson")
-        .about("Runs a single command in a container")
-        .arg(clap::Arg::with_name("build")
-             .short("b")
-             .long("build")
-             .help("Build the selected image")
-             .takes_value(false))
-        .arg(clap::Arg::with_name("image")
-             .short("i")
-             .long("image")
-             .help("Image of the container to run")
-             .takes_value(true))
-        .arg(clap::Arg::with_name("COMMAND")
-             .help("Command to be run in the container")
-             .multiple(true)
-             .required(false)
-             .index(1));
+    process::exit(codo());
+}
 
+fn codo() -> i32 {
     // Determine if any arguments were passed
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        match app.print_help() {
+        match app().print_help() {
             Ok(_) => (),
             Err(err) => error!("Failed to print help: {}", err)
         };
         println!();
-        return;
+        return codo_error::EXIT_SUCCESS;
     }
-
-    // Get a vector of argmuments to be parsed
-    let mut args_that_take_values: collections::HashSet<&str> = collections::HashSet::new();
-    args_that_take_values.insert("-i");
-    args_that_take_values.insert("--image");
-    let mut args_to_skip = 0;
-    let mut finished = false;
-    let mut input_command_index = 0;
-    let (clap_args, mut input_command): (Vec<String>, Vec<String>) = args[1..].iter()
-        .map(|v| v.to_owned())
-        .partition(|val| {
-        if finished {
-            return false;
-        }
-        input_command_index = input_command_index + 1;
-        if args_to_skip > 0 {
-            args_to_skip = args_to_skip - 1;
-            return true;
-        } 
-        if args_that_take_values.contains(val.as_str()) {
-            args_to_skip = args_to_skip + 1;
-            return true
-        } 
-        if (val.as_bytes()[0] as char) == '-' {
-            return true;
+    let matches = match app().get_matches_from_safe(args) {
+        Ok(matches) => matches,
+        // Help and version are written by clap as errors, but aren't failures
+        Err(err) if err.kind == clap::ErrorKind::HelpDisplayed || err.kind == clap::ErrorKind::VersionDisplayed => {
+            println!("{}", err.message);
+            return codo_error::EXIT_SUCCESS;
+        },
+        Err(err) => {
+            eprintln!("{}", err.message);
+            return codo_error::EXIT_USAGE;
         }
-        finished = true;
-        return false;
-    });
-    debug!("clap args: {:?}", clap_args);
-    debug!("Input command: {:?}", input_command);
-    debug!("Input command index: {}", input_command_index);
-    
-    // Get the command to be run
-    let matches = app.get_matches_from(clap_args);
+    };
 
-    // Get the image being used
+    // Keep stdout for the messages of a stdio tool
+    let stdio_output = match matches.subcommand() {
+        ("lsp", Some(_)) => match lsp::redirect_stdout() {
+            Ok(output) => Some(output),
+            Err(err) => return fail("Failed to redirect stdout", Box::new(err))
+        },
+        _ => None
+    };
+
+    // The doctor reports config errors itself
+    if let ("doctor", Some(_)) = matches.subcommand() {
+        return doctor::doctor();
+    }
+
+    // Read the config
     let codo_config = match config::codo_config() {
         Ok(ok) => ok,
         Err(err) => {
-            println!("Failed to read config file: {}", err);
-            return;
+            eprintln!("Failed to read config file: {}", err);
+            return codo_error::EXIT_INVALID_CONFIG;
         }
     };
-    let default_image_name = codo_config[config::DEFAULT_IMAGE]
-                .as_str()
-                .expect("Failed to get default image");
-    let image_name = arg_value(&matches, input_command_index, "image", default_image_name);
-    debug!("Image: {:?}", image_name);
 
-    // Build the image if the build argument was passed
-    let build_arg = arg_passed(&matches, input_command_index, "build");
-    debug!("Build: {:?}", build_arg);
-    if build_arg { 
-        match image::build(&image_name) {
-            Ok(_) => (),
+    // Commands that don't need a container engine
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        let image_name = config_matches.value_of("IMAGE").unwrap_or(&codo_config.default_image);
+        return match codo_config.with_image(image_name) {
+            Ok(image_config) => show_config(&image_config),
             Err(err) => {
-                println!("Failed to build image: {}", err);
-                return;
+                eprintln!("Failed to read config file: {}", err);
+                codo_error::EXIT_INVALID_CONFIG
             }
-        }; 
+        };
+    }
+
+    if let ("export", Some(export_matches)) = matches.subcommand() {
+        let commands: Vec<&str> = match export_matches.values_of("COMMAND") {
+            Some(values) => values.collect(),
+            None => Vec::new()
+        };
+        return if export_matches.is_present("list") {
+            shim::list(&codo_config)
+        } else if export_matches.is_present("rm") {
+            shim::remove(&codo_config, &commands)
+        } else {
+            let image_name = export_matches.value_of("image").unwrap_or(&codo_config.default_image);
+            shim::export(&codo_config, image_name, &commands)
+        };
     }
 
-    // Return if not given a command to run
-    if input_command.len() < 1 {
-        debug!("No arguments passed. Exiting.");
-        return;
+    if let ("lock", Some(lock_matches)) = matches.subcommand() {
+        if lock_matches.is_present("check") {
+            let image_names: Vec<&str> = lock_matches.values_of("IMAGE").map(|values| values.collect()).unwrap_or_default();
+            return lock::check(&codo_config, &image_names);
+        }
     }
 
-    // Build the container run command
-    let mut command_contents: Vec<String> = vec!["sudo", "docker", "run", "-ti", "--rm"]
-        .iter()
-        .map(|s| s.to_string())
-        .collect();
+    // Select the container engine
+    let engine = match engine::from_config(codo_config.container_engine, codo_config.privilege_escalation) {
+        Ok(engine) => engine,
+        Err(err) => return fail("Failed to select container engine", err)
+    };
+    debug!("Container engine: {}", engine.name());
 
-    // Add binding to working directory
-    let bind_param: String;
-    match env::current_dir() {
-        Ok(ok) => match ok.into_os_string().into_string() {
-            Ok(working_dir) => {
-                command_contents.push("-v".to_string());
-                bind_param = format!("{}:/codo", working_dir);
-                command_contents.push(bind_param);
-                command_contents.push("-w".to_string());
-                command_contents.push("/codo".to_string());
-            },
-            Err(err) => {
-                error!("Failed to get working directory: {:?}", err);
+    match matches.subcommand() {
+        ("run", Some(run_matches)) => {
+            let options = RunOptions::from_matches(&[&matches, run_matches]);
+            let input_command: Vec<String> = match run_matches.values_of("COMMAND") {
+                Some(values) => values.map(|s| s.to_string()).collect(),
+                None => Vec::new()
+            };
+            run::run(&codo_config, engine.as_ref(), &options, input_command)
+        },
+        ("lsp", Some(lsp_matches)) => {
+            let mut options = RunOptions::from_matches(&[&matches, lsp_matches]);
+            options.stdio_output = stdio_output;
+            let input_command: Vec<String> = match lsp_matches.values_of("COMMAND") {
+                Some(values) => values.map(|s| s.to_string()).collect(),
+                None => Vec::new()
+            };
+            run::run(&codo_config, engine.as_ref(), &options, input_command)
+        },
+        ("build", Some(build_matches)) => {
+            let image_name = build_matches.value_of("IMAGE").unwrap_or(&codo_config.default_image);
+            let mut image_config = match codo_config.with_image(image_name) {
+                Ok(image_config) => image_config,
+                Err(err) => {
+                    eprintln!("Failed to read config file: {}", err);
+                    return codo_error::EXIT_INVALID_CONFIG;
+                }
+            };
+            if let Some(pull) = build_matches.value_of("pull").and_then(image::PullPolicy::from_name) {
+                image_config.pull = pull;
+            }
+            match image::plan_build(image_name, &image_config).and_then(|plan| image::build(engine.as_ref(), &plan)) {
+                Ok(_) => codo_error::EXIT_SUCCESS,
+                Err(err) => fail("Failed to build image", err)
             }
         },
-        Err(err) => {
-            error!("Failed to get working directory: {}", err);
+        ("enter", Some(enter_matches)) => {
+            let options = RunOptions::from_matches(&[&matches, enter_matches]);
+            let shell: Vec<String> = session::ENTER_SHELL.iter().map(|s| s.to_string()).collect();
+            session::exec(&codo_config, engine.as_ref(), &options, shell)
+        },
+        ("exec", Some(exec_matches)) => {
+            let options = RunOptions::from_matches(&[&matches, exec_matches]);
+            let input_command: Vec<String> = match exec_matches.values_of("COMMAND") {
+                Some(values) => values.map(|s| s.to_string()).collect(),
+                None => Vec::new()
+            };
+            session::exec(&codo_config, engine.as_ref(), &options, input_command)
+        },
+        ("ps", Some(_)) => session::list(engine.as_ref()),
+        ("stop", Some(stop_matches)) => {
+            session::stop(&codo_config, engine.as_ref(), stop_matches.value_of("image"),
+                stop_matches.is_present("all"), stop_matches.is_present("rm"))
+        },
+        ("lock", Some(lock_matches)) => {
+            let image_names: Vec<&str> = lock_matches.values_of("IMAGE").map(|values| values.collect()).unwrap_or_default();
+            let update = false;
+            lock::lock(&codo_config, engine.as_ref(), &image_names, update)
+        },
+        ("update", Some(update_matches)) => {
+            let image_names: Vec<&str> = update_matches.values_of("IMAGE").map(|values| values.collect()).unwrap_or_default();
+            let update = true;
+            lock::lock(&codo_config, engine.as_ref(), &image_names, update)
+        },
+        ("images", Some(_)) => list_images(engine.as_ref()),
+        ("rm", Some(rm_matches)) => {
+            let image_names: Vec<&str> = match rm_matches.values_of("IMAGE") {
+                Some(values) => values.collect(),
+                None => Vec::new()
+            };
+            remove_images(engine.as_ref(), &image_names, rm_matches.is_present("all"))
+        },
+        ("prune", Some(prune_matches)) => {
+            prune::prune(engine.as_ref(), &prune::PruneOptions::from_matches(prune_matches))
+        },
+        ("home", Some(home_matches)) => {
+            let image_name = home_matches.value_of("IMAGE").unwrap_or(&codo_config.default_image);
+            manage_home(codo_config.persistent_home, engine.as_ref(), image_name, home_matches.is_present("rm"))
+        },
+        (external, external_matches) => {
+            // Anything else is a command to run
+            let options = RunOptions::from_matches(&[&matches]);
+            let mut input_command: Vec<String> = Vec::new();
+            if !external.is_empty() {
+                input_command.push(external.to_string());
+            }
+            if let Some(values) = external_matches.and_then(|m| m.values_of("")) {
+                input_command.extend(values.map(|s| s.to_string()));
+            }
+            run::run(&codo_config, engine.as_ref(), &options, input_command)
         }
-    };
+    }
+}
 
-    // Add the DISPLAY environmental variable
-    let display_param: String;
-    match env::var("DISPLAY") {
-        Ok(display) => {
-            command_contents.push("-e".to_string());
-            display_param = format!("DISPLAY={}", display);
-            command_contents.push(display_param);
-            command_contents.push("-v".to_string());
-            command_contents.push("/tmp/.X11-unix:/tmp/.X11-unix".to_string());
+fn show_config(codo_config: &config::Config) -> i32 {
+    // List the files the config was read from
+    if codo_config.layers.is_empty() {
+        println!("# No config files found, using defaults");
+    }
+    for layer in codo_config.layers.iter() {
+        println!("# {:?}: {}", layer.source, layer.file.display());
+    }
+    for key in config::Key::ALL.iter() {
+        let source = codo_config.source_of(*key);
+        if source != config::Source::Default.to_string() {
+            println!("# {} from {}", key.name(), source);
         }
-        Err(err) => error!("Failed to get environment variable DISPLAY: {}", err)
-    };
-
+    }
 
-    /*
-    // Create the storage directory
-    let home_dir: path::PathBuf;
-    match dirs::home_dir() {
-        Some(dir) => {
-            let mut image_storage_dir = dir;
-            image_storage_dir.push("codo");
-            image_storage_dir.push(image_name);
-            match fs::create_dir_all(image_storage_dir) {
-                Ok(ok) => (),
-                Err(err) => ()
-            }
+    match serde_yaml::to_string(codo_config) {
+        Ok(yaml) => {
+            print!("{}", yaml);
+            codo_error::EXIT_SUCCESS
         },
-        None => ()
-    };
-    */
+        Err(err) => fail("Failed to show config", Box::new(err))
+    }
+}
 
-    // Add the image name
-    let image_with_tag = image::add_codo_tag(&image_name);
-    let images_info = match image::images_info() {
+fn list_images(engine: &dyn ContainerEngine) -> i32 {
+    let images_info = match image::images_info(engine) {
         Ok(info) => info,
-        Err(err) => {
-            println!("Failed to get image info: {}", err);
-            return;
+        Err(err) => return fail("Failed to get image info", err)
+    };
+
+    println!("{:<40} {:<30} {:<14} {:<22} {:>10}", "REPOSITORY", "TAG", "IMAGE ID", "CREATED", "SIZE");
+    for image in images_info.iter().filter(|i| i.is_codo_build()) {
+        let id = image.id.trim_start_matches("sha256:");
+        println!("{:<40} {:<30} {:<14} {:<22} {:>10}",
+            image.repository.as_deref().unwrap_or("<none>"),
+            image.tag.as_deref().unwrap_or("<none>"),
+            &id[..id.len().min(12)],
+            &image.created[..image.created.len().min(19)],
+            image::human_size(image.size));
+    }
+
+    codo_error::EXIT_SUCCESS
+}
+
+fn remove_images(engine: &dyn ContainerEngine, image_names: &[&str], all: bool) -> i32 {
+    // Get the codo tags to remove
+    let images_with_tag: Vec<String> = if all {
+        match image::images_info(engine) {
+            Ok(info) => info.iter()
+                .filter(|i| i.is_codo_build())
+                .filter_map(|i| Some(format!("{}:{}", i.repository.as_ref()?, i.tag.as_ref()?)))
+                .collect(),
+            Err(err) => return fail("Failed to get image info", err)
         }
+    } else {
+        image_names.iter().map(|name| image::add_codo_tag(name)).collect()
     };
-    if !(images_info.contains_key(&image_with_tag)) {
-        match image::build(&image_name) {
-            Ok(_) => (),
-            Err(err) => {
-                println!("Failed to build image: {}", err);
-                return;
-            }
-        }; 
+    if images_with_tag.is_empty() {
+        println!("No codo images to remove");
+        return codo_error::EXIT_SUCCESS;
     }
-    command_contents.push(image_with_tag);
-    
-    // Add the input command
-    command_contents.append(&mut input_command);
 
-    // Start the container
-    debug!("Running {:?}", command_contents);
+    let mut remove_command = engine.command(&["rmi"]);
+    remove_command.extend(images_with_tag);
     let inherit_io = true;
-    match image::run_command(&command_contents, inherit_io) {
-        Ok(_) => (),
-        Err(err) => {
-            println!("Failed to execute command: {}", err);
+    match image::run_command(&remove_command, inherit_io) {
+        Ok(_) => codo_error::EXIT_SUCCESS,
+        Err(err) => fail("Failed to remove images", err)
+    }
+}
+
+fn manage_home(persistent_home: home::PersistentHome, engine: &dyn ContainerEngine, image_name: &str, remove: bool) -> i32 {
+    let location = match home::location(persistent_home, image_name) {
+        Some(location) => location,
+        None => {
+            println!("Persistent home is disabled. Set persistent-home to volume or directory in codo.yaml.");
+            return codo_error::EXIT_SUCCESS;
         }
     };
+
+    if !remove {
+        println!("{}", location);
+        return codo_error::EXIT_SUCCESS;
+    }
+    match home::remove(engine, persistent_home, image_name) {
+        Ok(_) => {
+            println!("Deleted {}", location);
+            codo_error::EXIT_SUCCESS
+        },
+        Err(err) => fail("Failed to delete persistent home", err)
+    }
 }
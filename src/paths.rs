@@ -0,0 +1,222 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::io::{self, Read, Write};
+
+// Internal
+use crate::run::Workspace;
+
+const READ_SIZE: usize = 8192;
+
+/// Translates paths between the mounted host directory and its mount point
+#[derive(Clone, Debug)]
+pub struct PathMap {
+    pub host_dir: String,
+    pub container_dir: String,
+}
+
+impl PathMap {
+    // Only needed when the workspace isn't mounted at its host path
+    pub fn new(workspace: &Workspace) -> Option<PathMap> {
+        let host_dir = workspace.host_dir.to_str()?.to_string();
+        let container_dir = workspace.container_dir.to_str()?.to_string();
+        if host_dir == container_dir {
+            return None;
+        }
+        Some(PathMap { host_dir, container_dir })
+    }
+
+    // Rewrite a host path argument, or the value of an option like --file=path
+    pub fn to_container_arg(&self, arg: &str) -> String {
+        if let Some(rest) = strip_dir(arg, &self.host_dir) {
+            return format!("{}{}", self.container_dir, rest);
+        }
+        if let Some((option, value)) = arg.split_once('=') {
+            if let Some(rest) = strip_dir(value, &self.host_dir) {
+                return format!("{}={}{}", option, self.container_dir, rest);
+            }
+        }
+        arg.to_string()
+    }
+
+    pub fn to_host_rewriter(&self) -> Rewriter {
+        Rewriter::new(self.container_dir.as_bytes(), self.host_dir.as_bytes())
+    }
+}
+
+// Get the rest of a path inside a directory
+fn strip_dir<'a>(path: &'a str, dir: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(dir)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+// Bytes that can be part of a file name in compiler output
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"._-+~@".contains(&byte)
+}
+
+/// Replaces a directory in a stream, holding back input that could be the start of a match
+pub struct Rewriter {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    pending: Vec<u8>,
+    // The last input bytes passed through, to check what comes before a match
+    history: Vec<u8>,
+}
+
+impl Rewriter {
+    pub fn new(from: &[u8], to: &[u8]) -> Rewriter {
+        Rewriter { from: from.to_vec(), to: to.to_vec(), pending: Vec::new(), history: Vec::new() }
+    }
+
+    pub fn push(&mut self, input: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(input);
+        self.rewrite(false)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.rewrite(true)
+    }
+
+    // The directory has to start a path, or follow the slashes of a URI like file://
+    fn starts_path(&self, pos: usize) -> bool {
+        let mut before: Vec<u8> = self.history.iter().chain(self.pending[..pos].iter()).rev().take(3).cloned().collect();
+        before.reverse();
+        match before.last() {
+            None => true,
+            Some(b'/') => before.ends_with(b"://"),
+            Some(byte) => !is_name_byte(*byte),
+        }
+    }
+
+    fn rewrite(&mut self, end: bool) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity(self.pending.len());
+        let mut pos = 0;
+        while pos < self.pending.len() {
+            let rest = &self.pending[pos..];
+
+            // Wait for more input if the rest could still become a match
+            if !end && rest.len() <= self.from.len() && self.from.starts_with(rest) {
+                break;
+            }
+
+            let matches = rest.starts_with(&self.from)
+                && !rest.get(self.from.len()).is_some_and(|byte| is_name_byte(*byte))
+                && self.starts_path(pos);
+            if matches {
+                output.extend_from_slice(&self.to);
+                pos += self.from.len();
+            } else {
+                output.push(rest[0]);
+                pos += 1;
+            }
+        }
+
+        // Only the last few bytes are needed to check the start of a path
+        self.history.extend(self.pending.drain(..pos));
+        let keep = self.history.len().saturating_sub(3);
+        self.history.drain(..keep);
+        output
+    }
+}
+
+/// Copy a stream, rewriting container paths as they pass through
+pub fn copy_rewritten(mut reader: impl Read, mut writer: impl Write, mut rewriter: Rewriter) -> io::Result<()> {
+    let mut buffer = [0; READ_SIZE];
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&rewriter.push(&buffer[..count]))?;
+        writer.flush()?;
+    }
+    writer.write_all(&rewriter.finish())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite_all(input: &[u8]) -> Vec<u8> {
+        let mut rewriter = Rewriter::new(b"/codo", b"/home/user/project");
+        let mut output = rewriter.push(input);
+        output.extend(rewriter.finish());
+        output
+    }
+
+    #[test]
+    fn rewrites_only_whole_paths() {
+        assert_eq!(rewrite_all(b"src at /codo/src/main.rs:3 and /codo"), b"src at /home/user/project/src/main.rs:3 and /home/user/project");
+        assert_eq!(rewrite_all(b"/codox/a x/codo/b ~/codo"), b"/codox/a x/codo/b ~/codo");
+        assert_eq!(rewrite_all(b"file:///codo/a \"/codo\""), b"file:///home/user/project/a \"/home/user/project\"");
+    }
+
+    // Hands out its data a few bytes at a time, like a pipe under load
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let count = self.chunk_size.min(self.data.len()).min(buffer.len());
+            buffer[..count].copy_from_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn rewrites_matches_split_across_reads() {
+        let input = b"error: /codo/src/lib.rs:10:5\n  --> /codo\nx/codo /codo/";
+        let expected = rewrite_all(input);
+        for chunk_size in 1..=7 {
+            let reader = ChunkedReader { data: input, chunk_size };
+            let mut output: Vec<u8> = Vec::new();
+            copy_rewritten(reader, &mut output, Rewriter::new(b"/codo", b"/home/user/project")).expect("copy failed");
+            assert_eq!(output, expected, "chunk size {}", chunk_size);
+        }
+        assert_eq!(expected, b"error: /home/user/project/src/lib.rs:10:5\n  --> /home/user/project\nx/codo /home/user/project/".to_vec());
+    }
+
+    #[test]
+    fn rewrites_option_values() {
+        let path_map = PathMap { host_dir: "/home/user/project".to_string(), container_dir: "/codo".to_string() };
+        assert_eq!(path_map.to_container_arg("/home/user/project/src"), "/codo/src");
+        assert_eq!(path_map.to_container_arg("--manifest-path=/home/user/project/Cargo.toml"), "--manifest-path=/codo/Cargo.toml");
+        assert_eq!(path_map.to_container_arg("/home/user/projects"), "/home/user/projects");
+    }
+}
@@ -0,0 +1,478 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::collections::BTreeMap;
+use std::error;
+use std::fs;
+use std::io::{self, Write};
+use std::path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Crate
+use log::{debug, error};
+
+// Internal
+use crate::codo_error::{self, fail};
+use crate::engine::ContainerEngine;
+use crate::image::{self, ImageInfo};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Which codo images `codo prune` removes
+#[derive(Debug, Default)]
+pub struct PruneOptions {
+    pub older_than: Option<u64>,
+    pub unused_for: Option<u64>,
+    pub keep: Option<usize>,
+    pub max_size: Option<u64>,
+    pub yes: bool,
+}
+
+impl PruneOptions {
+    // The values are checked by the validators of the arguments
+    pub fn from_matches(matches: &clap::ArgMatches) -> PruneOptions {
+        PruneOptions {
+            older_than: matches.value_of("older-than").and_then(|days| days.parse().ok()),
+            unused_for: matches.value_of("unused-for").and_then(|days| days.parse().ok()),
+            keep: matches.value_of("keep").and_then(|count| count.parse().ok()),
+            max_size: matches.value_of("max-size").and_then(|size| parse_size(size).ok()),
+            yes: matches.is_present("yes"),
+        }
+    }
+}
+
+pub fn prune_options<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("older-than")
+            .long("older-than")
+            .value_name("DAYS")
+            .help("Only remove images built more than DAYS days ago")
+            .validator(validate_count)
+            .takes_value(true),
+        clap::Arg::with_name("unused-for")
+            .long("unused-for")
+            .value_name("DAYS")
+            .help("Only remove images that codo hasn't run for DAYS days")
+            .validator(validate_count)
+            .takes_value(true),
+        clap::Arg::with_name("keep")
+            .long("keep")
+            .value_name("N")
+            .help("Keep the newest N builds of each image")
+            .validator(validate_count)
+            .takes_value(true),
+        clap::Arg::with_name("max-size")
+            .long("max-size")
+            .value_name("SIZE")
+            .help("Also remove the least recently used images until the rest fit in SIZE, like 20G")
+            .validator(|size| parse_size(&size).map(|_| ()))
+            .takes_value(true),
+        clap::Arg::with_name("yes")
+            .short("y")
+            .long("yes")
+            .help("Remove the images without asking"),
+    ]
+}
+
+fn validate_count(count: String) -> Result<(), String> {
+    count.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("{:?} isn't a whole number", count))
+}
+
+// Parse a size like 500M or 1.5GB, using the same units as `human_size`
+fn parse_size(size: &str) -> Result<u64, String> {
+    let trimmed = size.trim();
+    let unit_start = trimmed.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(unit_start);
+    let unit = unit.to_ascii_lowercase();
+    let multiplier: f64 = match unit.trim_end_matches('b') {
+        "" => 1.0,
+        "k" => 1e3,
+        "m" => 1e6,
+        "g" => 1e9,
+        "t" => 1e12,
+        _ => return Err(format!("{:?} isn't a size like 500M or 20G", size)),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(number) if number >= 0.0 => Ok((number * multiplier) as u64),
+        _ => Err(format!("{:?} isn't a size like 500M or 20G", size)),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Days since the Unix epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Parse an RFC 3339 timestamp like the engines' 2021-06-01T10:00:00.123456789+02:00
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.trim().split_once(['T', 't'])?;
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (year, month, day) = match date.as_slice() {
+        [year, month, day] => (*year, *month, *day),
+        _ => return None,
+    };
+
+    let zone_start = time.find(['Z', 'z', '+', '-']).unwrap_or(time.len());
+    let (clock, zone) = time.split_at(zone_start);
+    let clock = clock.split('.').next()?;
+    let clock: Vec<i64> = clock.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let seconds = match clock.as_slice() {
+        [hour, minute, second] => hour * 3600 + minute * 60 + second,
+        _ => return None,
+    };
+
+    let offset = match zone.chars().next() {
+        None | Some('Z') | Some('z') => 0,
+        Some(sign) => {
+            let (hours, minutes) = zone[1..].split_once(':')?;
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            if sign == '-' { -offset } else { offset }
+        }
+    };
+
+    Some(days_from_civil(year, month, day) * SECONDS_PER_DAY + seconds - offset)
+}
+
+fn last_run_file() -> Option<path::PathBuf> {
+    let mut last_run_file = dirs::data_dir()?;
+    last_run_file.push("codo");
+    last_run_file.push("last-run.json");
+    Some(last_run_file)
+}
+
+// When each codo image was last run, by its tag
+fn last_runs() -> BTreeMap<String, i64> {
+    let contents = match last_run_file().map(fs::read) {
+        Some(Ok(contents)) => contents,
+        _ => return BTreeMap::new(),
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(last_runs) => last_runs,
+        Err(err) => {
+            error!("Failed to read the last run times of images: {}", err);
+            BTreeMap::new()
+        }
+    }
+}
+
+fn write_last_runs(last_runs: &BTreeMap<String, i64>) -> Result<(), Box<dyn error::Error>> {
+    let last_run_file = match last_run_file() {
+        Some(file) => file,
+        None => {
+            let err = "Failed to get the data directory for the last run times of images";
+            return Err(Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, err)));
+        }
+    };
+    if let Some(dir) = last_run_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Replace the file in one step so concurrent runs never read half of it
+    let temp_file = last_run_file.with_extension(format!("json.{}", process::id()));
+    fs::write(&temp_file, serde_json::to_vec_pretty(last_runs)?)?;
+    fs::rename(&temp_file, &last_run_file)?;
+    Ok(())
+}
+
+/// Note that an image was run, so `codo prune --unused-for` keeps it
+pub fn record_run(image_with_tag: &str) {
+    let mut last_runs = last_runs();
+    last_runs.insert(image_with_tag.to_string(), now());
+    if let Err(err) = write_last_runs(&last_runs) {
+        error!("Failed to record the last run of {}: {}", image_with_tag, err);
+    }
+}
+
+// A codo image with what's known about its use
+struct Candidate {
+    info: ImageInfo,
+    image_name: String,
+    created: Option<i64>,
+    last_used: Option<i64>,
+    reason: Option<String>,
+}
+
+impl Candidate {
+    fn age_days(&self, now: i64) -> Option<i64> {
+        self.created.map(|created| (now - created) / SECONDS_PER_DAY)
+    }
+
+    // Images that were never run count as unused since they were built
+    fn unused_days(&self, now: i64) -> Option<i64> {
+        self.last_used.or(self.created).map(|time| (now - time) / SECONDS_PER_DAY)
+    }
+}
+
+fn format_days(days: Option<i64>) -> String {
+    match days {
+        Some(1) => "1 day".to_string(),
+        Some(days) => format!("{} days", days),
+        None => "unknown".to_string(),
+    }
+}
+
+// Mark the candidates to remove
+fn select(candidates: &mut [Candidate], options: &PruneOptions, now: i64) {
+    // The newest builds come first in each image
+    candidates.sort_by(|a, b| a.image_name.cmp(&b.image_name).then(b.created.cmp(&a.created)));
+    let mut protected: Vec<bool> = vec![false; candidates.len()];
+    if let Some(keep) = options.keep {
+        let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+        for (i, candidate) in candidates.iter().enumerate() {
+            let count = seen.entry(&candidate.image_name).or_insert(0);
+            protected[i] = *count < keep;
+            *count += 1;
+        }
+    }
+
+    let time_filters = options.older_than.is_some() || options.unused_for.is_some();
+    for (candidate, protected) in candidates.iter_mut().zip(protected.iter()) {
+        if *protected {
+            continue;
+        }
+        candidate.reason = if time_filters {
+            // Every filter given has to match
+            let age = candidate.age_days(now);
+            let unused = candidate.unused_days(now);
+            let old = options.older_than.map(|days| age.is_some_and(|age| age >= days as i64));
+            let idle = options.unused_for.map(|days| unused.is_some_and(|unused| unused >= days as i64));
+            match (old, idle) {
+                (Some(false), _) | (_, Some(false)) => None,
+                (Some(true), Some(true)) => Some(format!("built {} ago, unused for {}", format_days(age), format_days(unused))),
+                (Some(true), None) => Some(format!("built {} ago", format_days(age))),
+                _ => Some(format!("unused for {}", format_days(unused))),
+            }
+        } else if let Some(keep) = options.keep {
+            Some(format!("not among the newest {}", keep))
+        } else if candidate.info.tag.is_none() {
+            Some("replaced by a newer build".to_string())
+        } else {
+            None
+        };
+    }
+
+    // Drop the least recently used of the rest until they fit in the budget
+    if let Some(max_size) = options.max_size {
+        let mut size: u64 = candidates.iter()
+            .filter(|candidate| candidate.reason.is_none())
+            .map(|candidate| candidate.info.size)
+            .sum();
+        let mut order: Vec<usize> = (0..candidates.len())
+            .filter(|i| candidates[*i].reason.is_none() && !protected[*i])
+            .collect();
+        order.sort_by_key(|i| candidates[*i].last_used.or(candidates[*i].created));
+        for i in order {
+            if size <= max_size {
+                break;
+            }
+            size -= candidates[i].info.size;
+            candidates[i].reason = Some(format!("over the {} budget", image::human_size(max_size)));
+        }
+    }
+}
+
+fn candidates(engine: &dyn ContainerEngine) -> Result<Vec<Candidate>, Box<dyn error::Error>> {
+    let last_runs = last_runs();
+    let candidates: Vec<Candidate> = image::images_info(engine)?
+        .into_iter()
+        .filter(|info| info.is_codo_image())
+        .map(|info| {
+            // Podman keeps local builds under localhost/
+            let reference = info.reference();
+            let last_used = info.tag.as_ref()
+                .and_then(|_| last_runs.get(&reference).or_else(|| last_runs.get(reference.strip_prefix("localhost/")?)))
+                .cloned();
+            Candidate {
+                image_name: info.image_name().unwrap_or_else(|| info.reference()),
+                created: parse_timestamp(&info.created),
+                last_used,
+                reason: None,
+                info,
+            }
+        })
+        .collect();
+    Ok(candidates)
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    if io::stdout().flush().is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Remove the codo images picked by the options after showing what goes
+pub fn prune(engine: &dyn ContainerEngine, options: &PruneOptions) -> i32 {
+    let mut candidates = match candidates(engine) {
+        Ok(candidates) => candidates,
+        Err(err) => return fail("Failed to get image info", err)
+    };
+    let now = now();
+    select(&mut candidates, options, now);
+    debug!("Prune options: {:?}", options);
+
+    let (remove, keep): (Vec<Candidate>, Vec<Candidate>) = candidates.into_iter()
+        .partition(|candidate| candidate.reason.is_some());
+    if remove.is_empty() {
+        println!("No codo images to prune");
+        return codo_error::EXIT_SUCCESS;
+    }
+
+    // Show the summary first
+    println!("{:<40} {:<30} {:<14} {:<10} {:<10} {:>10}  REASON", "REPOSITORY", "TAG", "IMAGE ID", "AGE", "UNUSED", "SIZE");
+    for candidate in remove.iter() {
+        let id = candidate.info.id.trim_start_matches("sha256:");
+        println!("{:<40} {:<30} {:<14} {:<10} {:<10} {:>10}  {}",
+            candidate.info.repository.as_deref().unwrap_or("<none>"),
+            candidate.info.tag.as_deref().unwrap_or("<none>"),
+            &id[..id.len().min(12)],
+            format_days(candidate.age_days(now)),
+            format_days(candidate.unused_days(now)),
+            image::human_size(candidate.info.size),
+            candidate.reason.as_deref().unwrap_or_default());
+    }
+    let size = |candidates: &[Candidate]| image::human_size(candidates.iter().map(|c| c.info.size).sum());
+    println!();
+    println!("Removing {} images ({}) and keeping {} ({}). Sizes include layers shared with other images.",
+        remove.len(), size(&remove), keep.len(), size(&keep));
+
+    if !options.yes && !confirm("Remove these images?") {
+        println!("No images removed");
+        return codo_error::EXIT_SUCCESS;
+    }
+
+    // Carry on past images that can't be removed, like those of running containers
+    let mut exit_code = codo_error::EXIT_SUCCESS;
+    let mut last_runs = last_runs();
+    let inherit_io = false;
+    for candidate in remove.iter() {
+        let reference = candidate.info.reference();
+        match image::run_command(&engine.command(&["rmi", &reference]), inherit_io) {
+            Ok(_) => {
+                println!("Removed {}", reference);
+                last_runs.remove(&reference);
+            },
+            Err(err) => exit_code = fail(&format!("Failed to remove {}", reference), err),
+        }
+    }
+    if let Err(err) = write_last_runs(&last_runs) {
+        error!("Failed to update the last run times of images: {}", err);
+    }
+
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_with_offsets_and_fractions() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2021-06-01T08:00:00Z"), Some(1622534400));
+        assert_eq!(parse_timestamp("2021-06-01T10:00:00.123456789+02:00"), Some(1622534400));
+        assert_eq!(parse_timestamp("2021-06-01T02:30:00.5-05:30"), Some(1622534400));
+        assert_eq!(parse_timestamp("2021-06-01t08:00:00z"), Some(1622534400));
+        assert_eq!(parse_timestamp("2021-06-01"), None);
+        assert_eq!(parse_timestamp("2021-06-01T08:00+02:00"), None);
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("2kb"), Ok(2_000));
+        assert_eq!(parse_size("500M"), Ok(500_000_000));
+        assert_eq!(parse_size("1.5GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size(" 20g "), Ok(20_000_000_000));
+        assert_eq!(parse_size("1T"), Ok(1_000_000_000_000));
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    fn candidate(image_name: &str, created_day: i64, last_used_day: Option<i64>) -> Candidate {
+        let info = ImageInfo {
+            id: format!("{}-{}", image_name, created_day),
+            repository: Some(image_name.to_string()),
+            tag: Some(format!("day-{}", created_day)),
+            digest: None,
+            created: String::new(),
+            size: 100,
+            labels: Default::default(),
+        };
+        Candidate {
+            info,
+            image_name: image_name.to_string(),
+            created: Some(created_day * SECONDS_PER_DAY),
+            last_used: last_used_day.map(|day| day * SECONDS_PER_DAY),
+            reason: None,
+        }
+    }
+
+    fn selected(candidates: &[Candidate]) -> Vec<String> {
+        candidates.iter().filter(|c| c.reason.is_some()).map(|c| c.info.id.to_owned()).collect()
+    }
+
+    #[test]
+    fn max_size_drops_least_recently_used() {
+        let mut candidates = vec![candidate("x", 1, Some(9)), candidate("y", 2, Some(5)), candidate("z", 7, None)];
+        let options = PruneOptions { max_size: Some(150), ..PruneOptions::default() };
+        select(&mut candidates, &options, 10 * SECONDS_PER_DAY);
+        assert_eq!(selected(&candidates), vec!["y-2", "z-7"]);
+    }
+
+    #[test]
+    fn keep_protects_newest_from_max_size() {
+        let mut candidates = vec![candidate("a", 1, None), candidate("a", 3, None), candidate("a", 2, None), candidate("b", 1, None)];
+        let options = PruneOptions { keep: Some(1), older_than: Some(100), max_size: Some(100), ..PruneOptions::default() };
+        select(&mut candidates, &options, 10 * SECONDS_PER_DAY);
+        assert_eq!(selected(&candidates), vec!["a-2", "a-1"]);
+        for candidate in candidates.iter().filter(|c| c.reason.is_some()) {
+            assert_eq!(candidate.reason.as_deref(), Some("over the 100 B budget"));
+        }
+    }
+}
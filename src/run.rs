@@ -0,0 +1,623 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::env;
+use std::error;
+use std::fs;
+use std::path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Crates
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+// Internal
+use crate::codo_error::{self, fail};
+use crate::config::{self, Key, Source};
+use crate::engine::{ContainerEngine, EngineSetting};
+use crate::home;
+use crate::image;
+use crate::paths::PathMap;
+use crate::prune;
+
+// Labels every container and image codo creates
+pub const MANAGED_LABEL: &str = "codo.managed";
+
+// Where the working directory is mounted when it isn't mounted at its host path
+pub const CONTAINER_DIR: &str = "/codo";
+
+// Directories of the image that a host directory must not be mounted over
+const SYSTEM_DIRS: [&str; 17] = ["/bin", "/boot", "/dev", "/etc", "/lib", "/lib32", "/lib64", "/libx32",
+    "/opt", "/proc", "/root", "/run", "/sbin", "/srv", "/sys", "/usr", "/var"];
+
+/// Where the mounted host directory appears in the container
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountPoint {
+    Host,
+    Codo,
+}
+
+/// Which host directory is mounted into the container
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MountDir {
+    WorkingDirectory,
+    Repository,
+}
+
+/// Host directory mounted into a container, and where it's mounted
+pub struct Workspace {
+    pub host_dir: path::PathBuf,
+    pub container_dir: path::PathBuf,
+    source: String,
+    mount_point_source: String,
+}
+
+impl Workspace {
+    // Mount the base directory, or the repository it's in
+    pub fn new(codo_config: &config::Config, base_dir: &path::Path, base_source: &str) -> Workspace {
+        let repository_dir = match codo_config.mount_dir {
+            MountDir::WorkingDirectory => None,
+            MountDir::Repository => config::repository_dir(),
+        };
+        let (host_dir, source) = match repository_dir {
+            Some(dir) => (dir, format!("repository root, {}", codo_config.source_of(Key::MountDir))),
+            None => (base_dir.to_path_buf(), base_source.to_string()),
+        };
+
+        // Never mount over the root, system directories or home of the container
+        let conflict = match codo_config.mount_point {
+            MountPoint::Host => host_path_conflict(codo_config, &host_dir),
+            MountPoint::Codo => None,
+        };
+        let (container_dir, mount_point_source) = match (codo_config.mount_point, conflict) {
+            (MountPoint::Host, None) => (host_dir.to_owned(), codo_config.source_of(Key::MountPoint)),
+            (MountPoint::Host, Some(conflict)) => (path::PathBuf::from(CONTAINER_DIR), format!("fallback, {}", conflict)),
+            (MountPoint::Codo, _) => (path::PathBuf::from(CONTAINER_DIR), codo_config.source_of(Key::MountPoint)),
+        };
+
+        Workspace { host_dir, container_dir, source, mount_point_source }
+    }
+
+    // Get where a host path in the workspace is in the container
+    pub fn container_path(&self, host_path: &path::Path) -> path::PathBuf {
+        match host_path.strip_prefix(&self.host_dir) {
+            Ok(relative_path) if relative_path.as_os_str().is_empty() => self.container_dir.to_owned(),
+            Ok(relative_path) => self.container_dir.join(relative_path),
+            Err(_) => self.container_dir.to_owned(),
+        }
+    }
+}
+
+// Say why a host directory can't be mounted at its own path in the container
+fn host_path_conflict(codo_config: &config::Config, host_dir: &path::Path) -> Option<String> {
+    if host_dir.parent().is_none() {
+        return Some(format!("{} is the root directory", host_dir.display()));
+    }
+
+    // Directories in the host home are the user's, even where the home itself is under /var
+    let in_host_home = dirs::home_dir().is_some_and(|home| host_dir.starts_with(&home) && host_dir != home);
+    let system_dir = SYSTEM_DIRS.iter()
+        .map(path::Path::new)
+        .find(|dir| host_dir.starts_with(dir) || dir.starts_with(host_dir));
+    if let (Some(dir), false) = (system_dir, in_host_home) {
+        return Some(format!("{} would cover the image's {}", host_dir.display(), dir.display()));
+    }
+
+    // The container home holds the persistent home, so only a directory inside it can share it
+    let container_home = image::current_user()?.home();
+    let container_home = path::Path::new(&container_home);
+    let inside_home = host_dir.starts_with(container_home) && host_dir != container_home;
+    if container_home.starts_with(host_dir) || (inside_home && codo_config.persistent_home != home::PersistentHome::None) {
+        return Some(format!("{} overlaps the container home {}", host_dir.display(), container_home.display()));
+    }
+    None
+}
+
+// Options for choosing and building the image
+pub fn image_options<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("build")
+             .short("b")
+             .long("build")
+             .help("Build the selected image")
+             .takes_value(false),
+        clap::Arg::with_name("image")
+             .short("i")
+             .long("image")
+             .help("Image of the container to run")
+             .takes_value(true),
+        pull_option(),
+    ]
+}
+
+// Pull policy for the base images of a build, also accepted by `codo build`
+pub fn pull_option<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("pull")
+         .long("pull")
+         .value_name("POLICY")
+         .possible_values(&image::PULL_POLICIES)
+         .help("When to pull the base images of a build, overriding pull in codo.yaml")
+         .takes_value(true)
+}
+
+// Options for running a command, accepted before the command or after `run`
+pub fn run_options<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    let mut run_options = image_options();
+    run_options.extend(vec![
+        clap::Arg::with_name("tty")
+             .long("tty")
+             .overrides_with("no-tty")
+             .help("Allocate a terminal in the container even if codo isn't run from one or rewrite-paths is on"),
+        clap::Arg::with_name("no-tty")
+             .long("no-tty")
+             .overrides_with("tty")
+             .help("Don't allocate a terminal in the container"),
+        clap::Arg::with_name("dry-run")
+             .long("dry-run")
+             .help("Print the Dockerfile and engine commands instead of running them"),
+        clap::Arg::with_name("explain")
+             .long("explain")
+             .help("Print where the image, mounts and environment variables came from"),
+    ]);
+    run_options
+}
+
+pub struct RunOptions {
+    pub build: bool,
+    pub image: Option<String>,
+    pub pull: Option<image::PullPolicy>,
+    pub tty: Option<bool>,
+    pub dry_run: bool,
+    pub explain: bool,
+    // Where the messages of a stdio tool go, with codo's own output sent to stderr
+    pub stdio_output: Option<fs::File>,
+}
+
+impl RunOptions {
+    // Later matches take precedence over earlier ones
+    pub fn from_matches(matches: &[&clap::ArgMatches]) -> RunOptions {
+        RunOptions {
+            build: matches.iter().any(|m| m.is_present("build")),
+            image: matches.iter().rev().find_map(|m| m.value_of("image")).map(|s| s.to_string()),
+            pull: matches.iter().rev().find_map(|m| m.value_of("pull")).and_then(image::PullPolicy::from_name),
+            tty: matches.iter().rev().find_map(|m| {
+                if m.is_present("tty") {
+                    Some(true)
+                } else if m.is_present("no-tty") {
+                    Some(false)
+                } else {
+                    None
+                }
+            }),
+            dry_run: matches.iter().any(|m| m.is_present("dry-run")),
+            explain: matches.iter().any(|m| m.is_present("explain")),
+            stdio_output: None,
+        }
+    }
+}
+
+// Where each part of the run command came from
+#[derive(Default)]
+pub struct Explanation {
+    entries: Vec<(String, String)>,
+}
+
+impl Explanation {
+    pub fn add(&mut self, what: String, source: String) {
+        self.entries.push((what, source));
+    }
+
+    pub fn print(&self) {
+        let width = self.entries.iter().map(|(what, _)| what.len()).max().unwrap_or(0);
+        for (what, source) in self.entries.iter() {
+            eprintln!("{:<width$}  from {}", what, source, width = width);
+        }
+    }
+}
+
+/// Image a container is started from, with the config that applies to it
+pub struct ImageSetup {
+    pub image_name: String,
+    pub config: config::Config,
+    pub build_plan: image::BuildPlan,
+}
+
+// Choose the image and plan its build, giving the exit code on failure
+pub fn setup_image(codo_config: &config::Config, engine: &dyn ContainerEngine, options: &RunOptions, explanation: &mut Explanation) -> Result<ImageSetup, i32> {
+    // Get the image being used
+    let image_name = match &options.image {
+        Some(image_name) => {
+            explanation.add(format!("image {}", image_name), Source::CommandLine.to_string());
+            image_name.to_owned()
+        },
+        None => {
+            explanation.add(format!("image {}", codo_config.default_image), codo_config.source_of(Key::DefaultImage));
+            codo_config.default_image.to_owned()
+        }
+    };
+    debug!("Image: {:?}", image_name);
+    let engine_source = match codo_config.container_engine {
+        EngineSetting::Auto => format!("{}, detected", codo_config.source_of(Key::ContainerEngine)),
+        _ => codo_config.source_of(Key::ContainerEngine),
+    };
+    explanation.add(format!("engine {}", engine.name()), engine_source);
+
+    // Add the config for the image
+    let mut config = match codo_config.with_image(&image_name) {
+        Ok(image_config) => image_config,
+        Err(err) => {
+            eprintln!("Failed to read config file: {}", err);
+            return Err(codo_error::EXIT_INVALID_CONFIG);
+        }
+    };
+    match options.pull {
+        Some(pull) => {
+            explanation.add(format!("pull {}", pull), Source::CommandLine.to_string());
+            config.pull = pull;
+        },
+        None => explanation.add(format!("pull {}", config.pull), config.source_of(Key::Pull)),
+    };
+
+    // Get what the image should be built from
+    let build_plan = match image::plan_build(&image_name, &config) {
+        Ok(plan) => plan,
+        Err(err) => return Err(fail("Failed to prepare image build", err))
+    };
+
+    Ok(ImageSetup { image_name, config, build_plan })
+}
+
+// Build the image if it's missing or its inputs changed since it was built
+pub fn update_image(engine: &dyn ContainerEngine, build_plan: &image::BuildPlan) -> Result<(), Box<dyn error::Error>> {
+    let rebuild = match image::image_info(engine, &build_plan.image_with_tag)? {
+        Some(info) if build_plan.is_current(&info) => {
+            debug!("Found image {}", info);
+            false
+        },
+        Some(info) => {
+            debug!("Found stale image {}", info);
+            eprintln!("Rebuilding {} because its Dockerfile, build context or build settings changed", build_plan.image_with_tag);
+            true
+        },
+        None => true
+    };
+    if rebuild {
+        let build_lock = image::lock_build(build_plan)?;
+        // Another codo may have built the image while this one waited
+        if build_lock.waited {
+            if let Some(info) = image::image_info(engine, &build_plan.image_with_tag)? {
+                if build_plan.is_current(&info) {
+                    debug!("Image {} was built by another codo", info);
+                    return Ok(());
+                }
+            }
+        }
+        image::build_locked(engine, build_plan, &build_lock)?;
+    }
+    Ok(())
+}
+
+pub fn run(codo_config: &config::Config, engine: &dyn ContainerEngine, options: &RunOptions, input_command: Vec<String>) -> i32 {
+    let mut explanation = Explanation::default();
+    let setup = match setup_image(codo_config, engine, options, &mut explanation) {
+        Ok(setup) => setup,
+        Err(code) => return code
+    };
+
+    // Get the container run command
+    let container_name = container_name(&setup.image_name);
+    let (run_command, streams) = if input_command.is_empty() {
+        (None, image::Streams::Inherit)
+    } else {
+        let working_dir = match env::current_dir() {
+            Ok(dir) => dir,
+            Err(err) => return fail("Failed to get working directory", Box::new(err))
+        };
+        let workspace = Workspace::new(&setup.config, &working_dir, "working directory");
+        let managed_label = format!("{}=true", MANAGED_LABEL);
+        let path_map = path_map(&setup.config, options, &workspace, &mut explanation);
+        let terminal_args = terminal_args(options, path_map.as_ref(), &mut explanation);
+        let mut run_command = engine.command(&["run", "--rm", "--name", &container_name, "--label", &managed_label]);
+        run_command.extend(terminal_args.iter().cloned());
+        run_command.append(&mut container_args(&setup, engine, options, &workspace, &working_dir, &mut explanation));
+        run_command.push(setup.build_plan.image_with_tag.to_owned());
+        run_command.extend(rewrite_args(path_map.as_ref(), input_command));
+        (Some(run_command), streams(options, path_map, &terminal_args))
+    };
+
+    if options.explain {
+        explanation.print();
+    }
+    if options.dry_run {
+        let commands: Vec<(String, Vec<String>)> = run_command.into_iter()
+            .map(|command| ("Run command".to_string(), command))
+            .collect();
+        print_dry_run(engine, options, &setup.build_plan, &commands);
+        return codo_error::EXIT_SUCCESS;
+    }
+
+    // Build the image if the build argument was passed
+    debug!("Build: {:?}", options.build);
+    if options.build {
+        if let Err(err) = image::build(engine, &setup.build_plan) {
+            return fail("Failed to build image", err);
+        }
+    }
+
+    // Return if not given a command to run
+    let run_command = match run_command {
+        Some(run_command) => run_command,
+        None => {
+            debug!("No arguments passed. Exiting.");
+            return codo_error::EXIT_SUCCESS;
+        }
+    };
+
+    // Make sure the image is up to date
+    if !options.build {
+        if let Err(err) = update_image(engine, &setup.build_plan) {
+            return fail("Failed to update image", err);
+        }
+    }
+
+    // Create the persistent home directory
+    if let Err(err) = home::prepare(setup.config.persistent_home, &setup.image_name) {
+        return fail("Failed to set up persistent home", err);
+    }
+    prune::record_run(&setup.build_plan.image_with_tag);
+
+    // Start the container
+    debug!("Running {:?}", run_command);
+    match image::run_interactive(&run_command, streams) {
+        Ok(outcome) => {
+            debug!("Container exited with {}", outcome.status);
+            if outcome.is_abnormal() {
+                remove_container(engine, &container_name);
+            }
+            image::status_code(&outcome.status)
+        },
+        Err(err) => fail("Failed to execute command", err)
+    }
+}
+
+// Give each run a name it can be cleaned up by
+fn container_name(image_name: &str) -> String {
+    // Jobs in separate pid namespaces can share a daemon and a pid
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.subsec_nanos()).unwrap_or(0);
+    format!("codo-{}-{}-{}", home::storage_name(image_name), process::id(), nanos)
+}
+
+// Stop and remove a container left behind by an interrupted run
+fn remove_container(engine: &dyn ContainerEngine, container_name: &str) {
+    debug!("Removing container {}", container_name);
+    let remove_command = engine.command(&["rm", "--force", container_name]);
+    let inherit_io = false;
+    if let Err(err) = image::run_command(&remove_command, inherit_io) {
+        // The engine usually removes the container itself
+        debug!("Failed to remove container {}: {}", container_name, err);
+    }
+}
+
+// Print the Dockerfile and the commands that would be run, each under a heading
+pub fn print_dry_run(engine: &dyn ContainerEngine, options: &RunOptions, build_plan: &image::BuildPlan, commands: &[(String, Vec<String>)]) {
+    // Each build writes its Dockerfile to a new private directory
+    let temp_dockerfile_dir = image::temp_dir_template();
+    println!("# Dockerfile, written to {} when building", temp_dockerfile_dir.join("Dockerfile").display());
+    println!("{}", build_plan.dockerfile.trim_end());
+    println!();
+    for (heading, command) in image::pull_commands(engine, build_plan).iter() {
+        println!("# {}", heading);
+        println!("{}", image::quote_command(command));
+        println!();
+    }
+    if options.build {
+        println!("# Build command");
+    } else {
+        println!("# Build command, run if {} is missing or out of date", build_plan.image_with_tag);
+    }
+    println!("{}", image::quote_command(&image::build_command(engine, build_plan, &temp_dockerfile_dir)));
+    for (heading, command) in commands.iter() {
+        println!();
+        println!("# {}", heading);
+        println!("{}", image::quote_command(command));
+    }
+}
+
+// Check if a file descriptor is a terminal
+fn is_terminal(fd: libc::c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+// Check if a file descriptor is a pipe, file or socket that input can be read from
+fn is_readable_stream(fd: libc::c_int) -> bool {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return false;
+    }
+    matches!(stat.st_mode & libc::S_IFMT, libc::S_IFIFO | libc::S_IFREG | libc::S_IFSOCK)
+}
+
+// Choose -i and -t to match how codo was started
+pub fn terminal_args(options: &RunOptions, path_map: Option<&PathMap>, explanation: &mut Explanation) -> Vec<String> {
+    // Stdio tools talk over a plain pipe
+    if options.stdio_output.is_some() {
+        explanation.add("-i".to_string(), "stdio mode".to_string());
+        return vec!["-i".to_string()];
+    }
+
+    let stdin_terminal = is_terminal(libc::STDIN_FILENO);
+    let stdout_terminal = is_terminal(libc::STDOUT_FILENO);
+    debug!("stdin is a terminal: {}, stdout is a terminal: {}", stdin_terminal, stdout_terminal);
+
+    let (tty, tty_source) = match options.tty {
+        Some(tty) => (tty, Source::CommandLine.to_string()),
+        // Output with a terminal can't be rewritten, so rewriting wins unless --tty is given
+        None if stdin_terminal && stdout_terminal && path_map.is_some() => {
+            explanation.add("no -t, so output paths are rewritten".to_string(), "rewrite-paths, pass --tty for a terminal".to_string());
+            eprintln!("Warning: the container has no terminal so its output paths can be rewritten. Pass --tty for one.");
+            (false, String::new())
+        },
+        None => (stdin_terminal && stdout_terminal, "terminal detection".to_string()),
+    };
+    if tty && path_map.is_some() {
+        explanation.add("output paths not rewritten because of -t".to_string(), tty_source.to_owned());
+    }
+    let interactive = tty || stdin_terminal || is_readable_stream(libc::STDIN_FILENO);
+
+    let mut terminal_args: Vec<String> = Vec::new();
+    if interactive {
+        explanation.add("-i".to_string(), "stdin detection".to_string());
+        terminal_args.push("-i".to_string());
+    }
+    if tty {
+        explanation.add("-t".to_string(), tty_source);
+        terminal_args.push("-t".to_string());
+    }
+    terminal_args
+}
+
+// Get how paths are translated if path rewriting is on and the workspace is mounted elsewhere
+pub fn path_map(codo_config: &config::Config, options: &RunOptions, workspace: &Workspace, explanation: &mut Explanation) -> Option<PathMap> {
+    // Stdio tools always need the paths in their messages translated
+    let source = if options.stdio_output.is_some() {
+        "stdio mode".to_string()
+    } else if codo_config.rewrite_paths {
+        codo_config.source_of(Key::RewritePaths)
+    } else {
+        return None;
+    };
+    let path_map = PathMap::new(workspace)?;
+    explanation.add(format!("rewrite paths {} <-> {}", path_map.host_dir, path_map.container_dir), source);
+    Some(path_map)
+}
+
+// Rewrite host paths in the arguments of a command
+pub fn rewrite_args(path_map: Option<&PathMap>, input_command: Vec<String>) -> Vec<String> {
+    match path_map {
+        Some(path_map) => input_command.iter().map(|arg| path_map.to_container_arg(arg)).collect(),
+        None => input_command,
+    }
+}
+
+// Choose how the command's input and output are passed through
+pub fn streams(options: &RunOptions, path_map: Option<PathMap>, terminal_args: &[String]) -> image::Streams {
+    if let Some(stdio_output) = &options.stdio_output {
+        return match stdio_output.try_clone() {
+            Ok(output) => image::Streams::Messages(path_map, output),
+            Err(err) => {
+                error!("Failed to get the output for messages: {}", err);
+                image::Streams::Inherit
+            }
+        };
+    }
+
+    // The engine needs the real terminal to size the container's, so output is only rewritten without one
+    match path_map {
+        Some(_) if terminal_args.iter().any(|arg| arg == "-t") => {
+            eprintln!("Warning: output paths aren't rewritten when the container has a terminal");
+            image::Streams::Inherit
+        },
+        Some(path_map) => image::Streams::RewriteOutput(path_map),
+        None => image::Streams::Inherit,
+    }
+}
+
+// Arguments for running a container of an image with the workspace mounted
+pub fn container_args(setup: &ImageSetup, engine: &dyn ContainerEngine, options: &RunOptions, workspace: &Workspace, working_dir: &path::Path, explanation: &mut Explanation) -> Vec<String> {
+    let codo_config = &setup.config;
+    let mut command_contents: Vec<String> = engine.run_args();
+
+    // Add binding to the workspace
+    let container_working_dir = workspace.container_path(working_dir);
+    match (workspace.host_dir.to_str(), workspace.container_dir.to_str(), container_working_dir.to_str()) {
+        (Some(host_dir), Some(container_dir), Some(container_working_dir)) => {
+            let bind_param = format!("{}:{}", host_dir, container_dir);
+            explanation.add(format!("mount {}", bind_param), workspace.source.to_owned());
+            explanation.add(format!("mount point {}", container_dir), workspace.mount_point_source.to_owned());
+            command_contents.push("-v".to_string());
+            command_contents.push(bind_param);
+            command_contents.push("-w".to_string());
+            command_contents.push(container_working_dir.to_string());
+        },
+        _ => error!("Failed to mount {:?}: not valid UTF-8", workspace.host_dir)
+    };
+
+    // Add the DISPLAY environmental variable, which stdio tools have no use for
+    let display_param: String;
+    match env::var("DISPLAY") {
+        Ok(_) if options.stdio_output.is_some() => debug!("Not passing DISPLAY to a stdio tool"),
+        Ok(display) => {
+            command_contents.push("-e".to_string());
+            display_param = format!("DISPLAY={}", display);
+            explanation.add(format!("env {}", display_param), "host DISPLAY".to_string());
+            explanation.add("mount /tmp/.X11-unix:/tmp/.X11-unix".to_string(), "host DISPLAY".to_string());
+            command_contents.push(display_param);
+            command_contents.push("-v".to_string());
+            command_contents.push("/tmp/.X11-unix:/tmp/.X11-unix".to_string());
+        }
+        Err(err) => error!("Failed to get environment variable DISPLAY: {}", err)
+    };
+
+    // Add the persistent home directory
+    let home_args = home::mount_args(codo_config.persistent_home, &setup.image_name);
+    if let Some(home_mount) = home_args.get(1) {
+        explanation.add(format!("mount {}", home_mount), codo_config.source_of(Key::PersistentHome));
+    }
+    command_contents.extend(home_args);
+
+    // Add the mounts, environment variables and arguments from the config
+    let container_home = match image::current_user() {
+        Some(user) => user.home(),
+        None => "/root".to_string()
+    };
+    for (mount, source) in codo_config.mounts.iter().zip(codo_config.sources_of_items(Key::Mounts)) {
+        let volume_arg = mount.volume_arg(&container_home);
+        explanation.add(format!("mount {}", volume_arg), source);
+        command_contents.push("-v".to_string());
+        command_contents.push(volume_arg);
+    }
+    for (env_var, source) in codo_config.env.iter().zip(codo_config.sources_of_items(Key::Env)) {
+        match env_var.env_arg() {
+            Some(env_arg) => {
+                explanation.add(format!("env {}", env_arg), source);
+                command_contents.push("-e".to_string());
+                command_contents.push(env_arg);
+            },
+            None => debug!("Not passing {} because it isn't set", env_var.name)
+        }
+    }
+    for (run_arg, source) in codo_config.run_args.iter().zip(codo_config.sources_of_items(Key::RunArgs)) {
+        explanation.add(format!("run argument {}", run_arg), source);
+        command_contents.push(run_arg.to_owned());
+    }
+
+    command_contents
+}
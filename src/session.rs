@@ -0,0 +1,336 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::path;
+
+// Crate
+use log::debug;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// Internal
+use crate::codo_error::{self, fail};
+use crate::config;
+use crate::engine::ContainerEngine;
+use crate::home;
+use crate::image;
+use crate::prune;
+use crate::run::{self, RunOptions};
+
+// Labels of session containers
+pub const SESSION_LABEL: &str = "codo.session";
+const PROJECT_LABEL: &str = "codo.project";
+
+// Keeps a session running until it's stopped
+const KEEP_ALIVE: [&str; 3] = ["sh", "-c", "trap 'exit 0' TERM; while :; do sleep 3600 & wait $!; done"];
+
+// Shell started by `codo enter`
+pub const ENTER_SHELL: [&str; 3] = ["sh", "-c", "if command -v bash > /dev/null; then exec bash; else exec sh; fi"];
+
+/// A long-lived container shared by the commands run for an image in a project
+#[derive(Debug)]
+pub struct Session {
+    pub name: String,
+    pub image_name: String,
+    pub project: String,
+    pub image_id: String,
+    pub status: String,
+    pub running: bool,
+}
+
+// Fields shared by `docker container inspect` and `podman container inspect`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectOutput {
+    image: String,
+    state: InspectState,
+    config: Option<InspectConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectState {
+    status: String,
+    running: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectConfig {
+    labels: Option<HashMap<String, String>>,
+}
+
+impl Session {
+    fn from_inspect(inspect: InspectOutput) -> Option<Session> {
+        // Skip containers that aren't codo sessions
+        let labels = inspect.config?.labels?;
+        let label = |key: &str| labels.get(key).cloned().unwrap_or_default();
+        if label(run::MANAGED_LABEL) != "true" || label(SESSION_LABEL).is_empty() {
+            return None;
+        }
+
+        Some(Session {
+            name: label(SESSION_LABEL),
+            image_name: label(image::IMAGE_LABEL),
+            project: label(PROJECT_LABEL),
+            image_id: inspect.image,
+            status: inspect.state.status,
+            running: inspect.state.running,
+        })
+    }
+}
+
+// Name the session of an image in a project, which is only shared with the same user
+fn session_name(image_name: &str, project_dir: &path::Path) -> String {
+    let project_name = project_dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut project_hash = Sha256::new();
+    project_hash.update(image::codo_tag_suffix().as_bytes());
+    project_hash.update([0]);
+    project_hash.update(project_dir.to_string_lossy().as_bytes());
+    let project_hash = format!("{:x}", project_hash.finalize());
+    format!("codo-session-{}-{}-{}",
+        home::storage_name(image_name),
+        home::storage_name(&project_name),
+        &project_hash[..8])
+}
+
+/// List the codo sessions, optionally only the one with the given name
+pub fn sessions(engine: &dyn ContainerEngine, name: Option<&str>) -> Result<Vec<Session>, Box<dyn error::Error>> {
+    // Only look at containers labelled by codo
+    let filter = match name {
+        Some(name) => format!("label={}={}", SESSION_LABEL, name),
+        None => format!("label={}", SESSION_LABEL),
+    };
+    let mut ids_command = engine.command(&["ps", "--all", "--quiet", "--no-trunc", "--filter", &filter]);
+    // Leave the sessions of other users of the daemon alone
+    if let Some(user) = image::current_user() {
+        ids_command.push("--filter".to_string());
+        ids_command.push(format!("label={}={}", image::USER_LABEL, user.name));
+    }
+    let inherit_io = false;
+    let ids = image::run_command(&ids_command, inherit_io)?;
+    let ids: Vec<String> = String::from_utf8(ids.stdout)?
+        .lines()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut inspect_command = engine.command(&["container", "inspect"]);
+    inspect_command.extend(ids);
+    let inspect = image::run_command(&inspect_command, inherit_io)?;
+    let inspect: Vec<InspectOutput> = match serde_json::from_slice(&inspect.stdout) {
+        Ok(inspect) => inspect,
+        Err(err) => {
+            let err = format!("Failed to parse output of {:?}: {}", inspect_command, err);
+            let err = codo_error::Error::new(codo_error::ErrorKind::ContainerEngineFailure, &err);
+            return Err(Box::new(err));
+        }
+    };
+
+    let sessions: Vec<Session> = inspect.into_iter().filter_map(Session::from_inspect).collect();
+    debug!("Sessions: {:?}", sessions);
+    Ok(sessions)
+}
+
+/// Run a command in the session of an image, starting the session if needed
+pub fn exec(codo_config: &config::Config, engine: &dyn ContainerEngine, options: &RunOptions, input_command: Vec<String>) -> i32 {
+    let mut explanation = run::Explanation::default();
+    let setup = match run::setup_image(codo_config, engine, options, &mut explanation) {
+        Ok(setup) => setup,
+        Err(code) => return code
+    };
+
+    // Sessions are shared by the whole project
+    let working_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(err) => return fail("Failed to get working directory", Box::new(err))
+    };
+    let project_dir = config::project_dir().unwrap_or_else(|| working_dir.clone());
+    let session_name = session_name(&setup.image_name, &project_dir);
+    debug!("Session: {}", session_name);
+
+    // Get the commands to start the session and run the command in it
+    let managed_label = format!("{}=true", run::MANAGED_LABEL);
+    let session_label = format!("{}={}", SESSION_LABEL, session_name);
+    let image_label = format!("{}={}", image::IMAGE_LABEL, setup.image_name);
+    let project_label = format!("{}={}", PROJECT_LABEL, project_dir.display());
+    let user_name = image::current_user().map(|user| user.name).unwrap_or_default();
+    let user_label = format!("{}={}", image::USER_LABEL, user_name);
+    let workspace = run::Workspace::new(&setup.config, &project_dir, "project directory");
+    let mut start_command = engine.command(&["run", "--detach", "--name", &session_name,
+        "--label", &managed_label, "--label", &session_label, "--label", &image_label, "--label", &project_label, "--label", &user_label]);
+    start_command.append(&mut run::container_args(&setup, engine, options, &workspace, &workspace.host_dir, &mut explanation));
+    start_command.push(setup.build_plan.image_with_tag.to_owned());
+    start_command.extend(KEEP_ALIVE.iter().map(|s| s.to_string()));
+
+    let container_dir = workspace.container_path(&working_dir);
+    let path_map = run::path_map(&setup.config, options, &workspace, &mut explanation);
+    let terminal_args = run::terminal_args(options, path_map.as_ref(), &mut explanation);
+    let mut exec_command = engine.command(&["exec"]);
+    exec_command.extend(terminal_args.iter().cloned());
+    exec_command.push("-w".to_string());
+    exec_command.push(container_dir.to_string_lossy().into_owned());
+    exec_command.push(session_name.to_owned());
+    exec_command.extend(run::rewrite_args(path_map.as_ref(), input_command));
+    let streams = run::streams(options, path_map, &terminal_args);
+
+    if options.explain {
+        explanation.print();
+    }
+    if options.dry_run {
+        let commands = vec![
+            (format!("Session start command, run if {} doesn't exist", session_name), start_command),
+            ("Exec command".to_string(), exec_command),
+        ];
+        run::print_dry_run(engine, options, &setup.build_plan, &commands);
+        return codo_error::EXIT_SUCCESS;
+    }
+
+    // Make sure the image is up to date
+    let updated = if options.build {
+        image::build(engine, &setup.build_plan)
+    } else {
+        run::update_image(engine, &setup.build_plan)
+    };
+    if let Err(err) = updated {
+        return fail("Failed to update image", err);
+    }
+
+    // Start the session if it isn't running
+    let session = match sessions(engine, Some(&session_name)) {
+        Ok(sessions) => sessions.into_iter().next(),
+        Err(err) => return fail("Failed to get sessions", err)
+    };
+    let inherit_io = false;
+    let started = match session {
+        Some(session) if session.running => {
+            warn_if_stale(engine, &session, &setup.build_plan);
+            Ok(())
+        },
+        Some(session) => {
+            warn_if_stale(engine, &session, &setup.build_plan);
+            debug!("Restarting session {}", session.name);
+            image::run_command(&engine.command(&["start", &session.name]), inherit_io).map(|_| ())
+        },
+        None => {
+            if let Err(err) = home::prepare(setup.config.persistent_home, &setup.image_name) {
+                return fail("Failed to set up persistent home", err);
+            }
+            debug!("Starting session {}", session_name);
+            image::run_command(&start_command, inherit_io).map(|_| ())
+        }
+    };
+    if let Err(err) = started {
+        return fail("Failed to start session", err);
+    }
+
+    // Run the command in the session
+    prune::record_run(&setup.build_plan.image_with_tag);
+    debug!("Running {:?}", exec_command);
+    match image::run_interactive(&exec_command, streams) {
+        Ok(outcome) => image::status_code(&outcome.status),
+        Err(err) => fail("Failed to execute command", err)
+    }
+}
+
+// Point out sessions started from an older build of their image
+fn warn_if_stale(engine: &dyn ContainerEngine, session: &Session, build_plan: &image::BuildPlan) {
+    match image::image_info(engine, &build_plan.image_with_tag) {
+        Ok(Some(info)) if info.id != session.image_id => {
+            eprintln!("Session {} uses an older build of {}. Run `codo stop --rm` to start a new one.",
+                session.name, build_plan.image_with_tag);
+        },
+        Ok(_) => (),
+        Err(err) => debug!("Failed to check the image of session {}: {}", session.name, err),
+    }
+}
+
+pub fn list(engine: &dyn ContainerEngine) -> i32 {
+    let sessions = match sessions(engine, None) {
+        Ok(sessions) => sessions,
+        Err(err) => return fail("Failed to get sessions", err)
+    };
+
+    println!("{:<50} {:<30} {:<10} PROJECT", "NAME", "IMAGE", "STATUS");
+    for session in sessions.iter() {
+        println!("{:<50} {:<30} {:<10} {}", session.name, session.image_name, session.status, session.project);
+    }
+
+    codo_error::EXIT_SUCCESS
+}
+
+/// Stop the session of an image in the current project, or every session
+pub fn stop(codo_config: &config::Config, engine: &dyn ContainerEngine, image_name: Option<&str>, all: bool, remove: bool) -> i32 {
+    let name = if all {
+        None
+    } else {
+        let image_name = image_name.unwrap_or(&codo_config.default_image);
+        let project_dir = match config::project_dir() {
+            Some(dir) => dir,
+            None => match env::current_dir() {
+                Ok(dir) => dir,
+                Err(err) => return fail("Failed to get working directory", Box::new(err))
+            }
+        };
+        Some(session_name(image_name, &project_dir))
+    };
+    let sessions = match sessions(engine, name.as_deref()) {
+        Ok(sessions) => sessions,
+        Err(err) => return fail("Failed to get sessions", err)
+    };
+    if sessions.is_empty() {
+        println!("No codo sessions to stop");
+        return codo_error::EXIT_SUCCESS;
+    }
+
+    let names: Vec<&str> = sessions.iter().map(|session| session.name.as_str()).collect();
+    let mut stop_command = if remove {
+        engine.command(&["rm", "--force"])
+    } else {
+        engine.command(&["stop"])
+    };
+    stop_command.extend(names.iter().map(|name| name.to_string()));
+    let inherit_io = false;
+    match image::run_command(&stop_command, inherit_io) {
+        Ok(_) => {
+            for name in names.iter() {
+                println!("{} {}", if remove { "Removed" } else { "Stopped" }, name);
+            }
+            codo_error::EXIT_SUCCESS
+        },
+        Err(err) => fail("Failed to stop sessions", err)
+    }
+}
@@ -0,0 +1,207 @@
+/*
+
+Copyright (c) 2021 Lyndsey Dickson (lyndseyrd@gmail.com)
+
+Permission is hereby granted, free of charge, to any person
+obtaining a copy of this software and associated documentation
+files (the "Software"), to deal in the Software without
+restriction, including without limitation the rights to use,
+copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the
+Software is furnished to do so, subject to the following
+conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+*/
+
+// Standard
+use std::env;
+use std::error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path;
+
+// Crate
+use log::debug;
+
+// Internal
+use crate::codo_error::{self, fail};
+use crate::config;
+use crate::engine;
+use crate::image;
+
+// Second line of every shim, followed by the image it runs in
+const SHIM_MARKER: &str = "# codo shim for image ";
+
+// Programs codo runs itself, so a shim for them would call back into codo
+const RESERVED_COMMANDS: [&str; 6] = ["codo", engine::DOCKER, engine::PODMAN, engine::SUDO, engine::DOAS, engine::ENV];
+
+/// A host command that runs inside an image
+#[derive(Debug)]
+pub struct Shim {
+    pub command: String,
+    pub image_name: String,
+    pub path: path::PathBuf,
+}
+
+impl Shim {
+    // Read a file in the shim directory, skipping files codo didn't write
+    fn read(path: &path::Path) -> Option<Shim> {
+        let contents = fs::read_to_string(path).ok()?;
+        let image_name = contents.lines().nth(1)?.strip_prefix(SHIM_MARKER)?;
+        Some(Shim {
+            command: path.file_name()?.to_string_lossy().into_owned(),
+            image_name: image_name.trim().to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn contents(&self, codo_exe: &path::Path) -> String {
+        let run_command = vec![
+            codo_exe.to_string_lossy().into_owned(),
+            "run".to_string(),
+            "-i".to_string(),
+            self.image_name.to_owned(),
+            "--".to_string(),
+            self.command.to_owned(),
+        ];
+        format!("#!/bin/sh\n{}{}\nexec {} \"$@\"\n", SHIM_MARKER, self.image_name, image::quote_command(&run_command))
+    }
+}
+
+fn shim_dir(codo_config: &config::Config) -> Result<path::PathBuf, Box<dyn error::Error>> {
+    match codo_config.shim_dir() {
+        Some(dir) => Ok(dir),
+        None => {
+            let err = "Failed to get the home directory for shim-dir";
+            Err(Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, err)))
+        }
+    }
+}
+
+/// List the shims in the shim directory
+pub fn shims(codo_config: &config::Config) -> Result<Vec<Shim>, Box<dyn error::Error>> {
+    let shim_dir = shim_dir(codo_config)?;
+    if !shim_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut shims: Vec<Shim> = Vec::new();
+    for entry in fs::read_dir(&shim_dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            if let Some(shim) = Shim::read(&path) {
+                shims.push(shim);
+            }
+        }
+    }
+    shims.sort_by(|a, b| a.command.cmp(&b.command));
+    debug!("Shims: {:?}", shims);
+    Ok(shims)
+}
+
+fn check_command(command: &str) -> Result<(), Box<dyn error::Error>> {
+    let err = if command.is_empty() || command.contains('/') || command.starts_with('.') {
+        format!("{:?} isn't a command name", command)
+    } else if RESERVED_COMMANDS.contains(&command) {
+        format!("{} can't be exported because codo runs it itself", command)
+    } else {
+        return Ok(());
+    };
+    Err(Box::new(codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err)))
+}
+
+pub fn export(codo_config: &config::Config, image_name: &str, commands: &[&str]) -> i32 {
+    let shim_dir = match shim_dir(codo_config) {
+        Ok(dir) => dir,
+        Err(err) => return fail("Failed to export commands", err)
+    };
+    let codo_exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return fail("Failed to find the codo executable", Box::new(err))
+    };
+    if let Err(err) = fs::create_dir_all(&shim_dir) {
+        return fail("Failed to create shim directory", Box::new(err));
+    }
+
+    for command in commands.iter() {
+        if let Err(err) = check_command(command) {
+            return fail("Failed to export command", err);
+        }
+
+        // Never replace a program that codo didn't write
+        let path = shim_dir.join(command);
+        if path.exists() && Shim::read(&path).is_none() {
+            let err = format!("{} already exists and isn't a codo shim", path.display());
+            let err = codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err);
+            return fail("Failed to export command", Box::new(err));
+        }
+
+        let shim = Shim { command: command.to_string(), image_name: image_name.to_string(), path };
+        let written = fs::write(&shim.path, shim.contents(&codo_exe))
+            .and_then(|_| fs::set_permissions(&shim.path, fs::Permissions::from_mode(0o755)));
+        if let Err(err) = written {
+            return fail("Failed to write shim", Box::new(err));
+        }
+        println!("Exported {} from {} to {}", shim.command, shim.image_name, shim.path.display());
+    }
+
+    // The shims only take effect if they come first in the PATH
+    let in_path = env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir == shim_dir))
+        .unwrap_or(false);
+    if !in_path {
+        eprintln!("{} isn't in PATH. Add it to run the exported commands.", shim_dir.display());
+    }
+
+    codo_error::EXIT_SUCCESS
+}
+
+pub fn list(codo_config: &config::Config) -> i32 {
+    let shims = match shims(codo_config) {
+        Ok(shims) => shims,
+        Err(err) => return fail("Failed to list shims", err)
+    };
+
+    println!("{:<20} {:<30} PATH", "COMMAND", "IMAGE");
+    for shim in shims.iter() {
+        println!("{:<20} {:<30} {}", shim.command, shim.image_name, shim.path.display());
+    }
+
+    codo_error::EXIT_SUCCESS
+}
+
+pub fn remove(codo_config: &config::Config, commands: &[&str]) -> i32 {
+    let shims = match shims(codo_config) {
+        Ok(shims) => shims,
+        Err(err) => return fail("Failed to remove shims", err)
+    };
+
+    for command in commands.iter() {
+        let shim = match shims.iter().find(|shim| shim.command == *command) {
+            Some(shim) => shim,
+            None => {
+                let err = format!("{} isn't an exported command", command);
+                let err = codo_error::Error::new(codo_error::ErrorKind::InvalidConfig, &err);
+                return fail("Failed to remove shim", Box::new(err));
+            }
+        };
+        if let Err(err) = fs::remove_file(&shim.path) {
+            return fail("Failed to remove shim", Box::new(err));
+        }
+        println!("Removed {}", shim.path.display());
+    }
+
+    codo_error::EXIT_SUCCESS
+}